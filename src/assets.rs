@@ -1,10 +1,15 @@
-use bevy::prelude::*;
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
 
-use crate::animation::{Animation, AnimationAsset};
+use crate::{
+    animation::{Animation, AnimationAsset},
+    world::{autotile::TilingRules, tileset::Tileset},
+};
 
 pub const TILE_SIZE: u32 = 70;
-pub const SHEET_W: u16 = 28;
-pub const SHEET_H: u16 = 59;
 pub const PIXEL_MODEL_TRANSFORM: Transform = Transform::from_scale(Vec3::new(
     1.0 / TILE_SIZE as f32,
     1.0 / TILE_SIZE as f32,
@@ -21,6 +26,68 @@ pub struct SpriteAssets {
     pub kenney_pixel_font: Handle<Font>,
     pub text_style: TextStyle,
     pub blank_texture: Handle<Image>,
+    pub tiling_rules: Handle<TilingRulesAsset>,
+    pub tileset: Handle<TilesetAsset>,
+}
+
+#[derive(TypeUuid, Deref)]
+#[uuid = "5b0f7e3c-2d8a-4c61-9f3e-8a1d6b4c7e20"]
+pub struct TilingRulesAsset(pub TilingRules);
+
+// Reads autotiling rules from `.tiling.ron` files, warning about anything in them that looks
+// like a mistake
+#[derive(Default)]
+pub struct TilingRulesLoader;
+
+impl AssetLoader for TilingRulesLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let rules = TilingRules::from_ron(bytes)?;
+            for problem in rules.validate() {
+                warn!("{}: {}", load_context.path().display(), problem);
+            }
+            load_context.set_default_asset(LoadedAsset::new(TilingRulesAsset(rules)));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tiling.ron"]
+    }
+}
+
+#[derive(TypeUuid, Deref)]
+#[uuid = "c83a1f52-6e0d-4b97-a2d4-1f7e9b3c5a68"]
+pub struct TilesetAsset(pub Tileset);
+
+// Reads where each tile is on the sheet from `.tileset.ron` files, warning about tiles left out
+// or overlapping
+#[derive(Default)]
+pub struct TilesetLoader;
+
+impl AssetLoader for TilesetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let tileset = Tileset::from_ron(bytes)?;
+            for problem in tileset.validate() {
+                warn!("{}: {}", load_context.path().display(), problem);
+            }
+            load_context.set_default_asset(LoadedAsset::new(TilesetAsset(tileset)));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tileset.ron"]
+    }
 }
 
 pub fn setup_sprites(
@@ -74,6 +141,8 @@ pub fn setup_sprites(
     };
 
     let blank_texture = assets.load("1x1.png");
+    let tiling_rules = assets.load("ground.tiling.ron");
+    let tileset = assets.load("numbering.tileset.ron");
 
     commands.insert_resource(SpriteAssets {
         tile_texture: texture_handle,
@@ -84,6 +153,8 @@ pub fn setup_sprites(
         kenney_pixel_font,
         text_style,
         blank_texture,
+        tiling_rules,
+        tileset,
     });
 }
 
@@ -248,21 +248,33 @@ pub fn n_to_bool(n: f64) -> bool {
     n < 0.5
 }
 
+// the n_to_* functions take noise in [0, 1]; value noise can hit 1 exactly on lattice points
 pub fn n_to_enum<T: Sequence + FromPrimitive>(n: f64) -> T {
-    T::from_u32(((T::CARDINALITY as f64) * n).floor() as u32).unwrap()
+    let i = ((T::CARDINALITY as f64) * n).floor() as u32;
+    T::from_u32(i.min(T::CARDINALITY as u32 - 1)).unwrap()
 }
 
 pub fn n_to_slice<T: Copy>(n: f64, slice: &[T]) -> T {
-    slice[(((slice.len() as f64) * n).floor()) as usize]
+    slice[((((slice.len() as f64) * n).floor()) as usize).min(slice.len() - 1)]
 }
 
 pub fn n_to_range<T: PrimInt + FromPrimitive>(n: f64, top: T) -> T {
     T::from_f64(n.floor()).unwrap() % top
 }
 
+// one of `0..top`, spread evenly over the noise
+pub fn n_to_index<T: PrimInt + FromPrimitive>(n: f64, top: T) -> T {
+    T::from_f64((n * top.to_f64().unwrap()).floor())
+        .unwrap()
+        .min(top - T::one())
+}
+
 pub fn n_to_box1<T: PrimInt + FromPrimitive>(n: f64, box1: Box1<T>) -> T {
     assert!(box1.size() > T::zero());
-    let out = T::from_f64((n * box1.size().to_f64().unwrap()).floor()).unwrap() + box1.lo_incl;
+    let out = T::from_f64((n * box1.size().to_f64().unwrap()).floor())
+        .unwrap()
+        .min(box1.size() - T::one())
+        + box1.lo_incl;
     assert!(box1.contains(out));
     out
 }
@@ -19,12 +19,17 @@ mod camera;
 mod helpers;
 mod world;
 use animation::{AnimationAsset, AnimationPlugin, AnimationState};
-use assets::{setup_sprites, SpriteAssets, P1_WALK01, PIXEL_MODEL_TRANSFORM, TILE_SIZE};
+use assets::{
+    setup_sprites, SpriteAssets, TilesetAsset, TilesetLoader, TilingRulesAsset, TilingRulesLoader,
+    P1_WALK01, PIXEL_MODEL_TRANSFORM, TILE_SIZE,
+};
 use camera::*;
 use world::{
     add_level_resource,
     brushes::Gen,
+    feature::LevelSchema,
     player::{keyboard_input_system, setup_camera, setup_player, control_switch_input_system},
+    tileset::{png_size, Tileset},
     LevelResource,
 };
 
@@ -35,6 +40,18 @@ enum GameState {
 }
 
 fn main() {
+    if let Some(sheet) = std::env::args()
+        .skip_while(|arg| arg != "--update-tileset")
+        .nth(1)
+    {
+        update_tileset(&sheet);
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--tileset-report") {
+        let bytes = std::fs::read(TILESET_PATH).expect("tileset manifest should be readable");
+        report_tileset(&Tileset::from_ron(&bytes).expect("tileset manifest should parse"));
+        return;
+    }
     //debug_graph();
     App::new()
         .insert_resource(WgpuSettings {
@@ -72,11 +89,17 @@ fn main() {
         .add_plugin(TweeningPlugin)
         .add_plugin(AnimationPlugin)
         .add_asset::<AnimationAsset>()
+        .add_asset::<TilingRulesAsset>()
+        .init_asset_loader::<TilingRulesLoader>()
+        .add_asset::<TilesetAsset>()
+        .init_asset_loader::<TilesetLoader>()
         .add_startup_system(add_level_resource)
         .insert_resource(ClearColor(SKY_COLOR))
         .add_enter_system(GameState::Splash, setup_sprites)
         .add_system(update_clear_colour.run_in_state(GameState::Splash))
-        .init_resource::<Gen>()
+        .insert_resource(world_gen())
+        .add_startup_system(log_seed)
+        .init_resource::<LevelSchema>()
         .add_enter_system_set(
             GameState::Level,
             ConditionSet::new()
@@ -89,6 +112,7 @@ fn main() {
             ConditionSet::new()
                 .run_in_state(GameState::Level)
                 .with_system(world::chunk_loader)
+                .with_system(world::liquid_flow)
                 .with_system(control_switch_input_system)
                 .with_system(keyboard_input_system)
                 .into(),
@@ -96,6 +120,55 @@ fn main() {
         .run();
 }
 
+// A fixed seed can be passed as `--seed <n>` or through the WORLD_SEED environment variable
+fn world_gen() -> Gen {
+    let seed = std::env::args()
+        .skip_while(|arg| arg != "--seed")
+        .nth(1)
+        .or_else(|| std::env::var("WORLD_SEED").ok());
+    match seed {
+        Some(s) => Gen::from_seed(s.parse().expect("world seed should be a u32")),
+        None => Gen::default(),
+    }
+}
+
+const TILESET_PATH: &str = "assets/numbering.tileset.ron";
+
+// Sizes the tileset manifest to `sheet` and lists the tiles added to `Tile` since, without a
+// cell, then reports on it. Nothing is read off the sheet but its size, so new tiles' cells are
+// filled in by hand. `--tileset-report` reports without writing
+fn update_tileset(sheet: &str) {
+    let bytes = std::fs::read(sheet).expect("tile sheet should be readable");
+    let (w, h) = png_size(&bytes).expect("tile sheet should be a PNG");
+    let (columns, rows) = ((w / TILE_SIZE) as u16, (h / TILE_SIZE) as u16);
+
+    let old = std::fs::read(TILESET_PATH).expect("tileset manifest should be readable");
+    let tileset = Tileset::from_ron(&old).expect("tileset manifest should parse");
+    let new = tileset.to_ron(columns, rows);
+    std::fs::write(TILESET_PATH, &new).expect("tileset manifest should be writable");
+
+    report_tileset(&Tileset::from_ron(new.as_bytes()).expect("written manifest should parse"));
+}
+
+// what's still wrong with the manifest and which cells go unused
+fn report_tileset(tileset: &Tileset) {
+    for problem in tileset.validate() {
+        println!("{}", problem);
+    }
+    for t in tileset.round_trip_failures() {
+        println!("{:?} doesn't come back from its cell", t);
+    }
+    let unused = tileset.unused_cells();
+    println!("{} cells have no tile:", unused.len());
+    for (x, y) in unused {
+        println!("    ({}, {})", x, y);
+    }
+}
+
+fn log_seed(gen: Res<Gen>) {
+    info!("world seed: {}", gen.seed);
+}
+
 const SKY_COLOR: Color = Color::rgb_linear(0.2, 0.6, 1.0);
 
 fn update_clear_colour(mut commands: Commands) {
@@ -0,0 +1,476 @@
+use std::{collections::HashMap, fmt};
+
+use itertools::iproduct;
+use serde::Deserialize;
+
+use super::tile::*;
+
+// The shape of the ground around a ground tile: which sides have no ground of its terrain, and
+// whether the cells diagonally under it don't either. Cover is left out, bar whether there is any
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Shape {
+    pub left: bool,
+    pub right: bool,
+    pub top: bool,
+    pub bottom: bool,
+    pub below_left: bool,
+    pub below_right: bool,
+    pub covered: bool,
+}
+
+// which sides of a ground tile face away from ground of its own terrain
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sides {
+    pub left: bool,
+    pub right: bool,
+    pub top: bool,
+    pub bottom: bool,
+}
+
+// what meets a ground tile at each of its corners
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Corners {
+    pub tl: TilingCorner,
+    pub tr: TilingCorner,
+    pub bl: TilingCorner,
+    pub br: TilingCorner,
+}
+
+// A pattern over some neighbourhood of a tile, where anything left out matches anything
+pub trait Mask {
+    type Hood: Copy + fmt::Debug;
+
+    // every neighbourhood there is, for checking rules against
+    fn every() -> Vec<Self::Hood>;
+    fn matches(&self, n: Self::Hood) -> bool;
+    // how many parts of the neighbourhood the mask pins down
+    fn specificity(&self) -> usize;
+}
+
+fn fits<T: PartialEq>(mask: Option<T>, value: T) -> bool {
+    mask.map_or(true, |m| m == value)
+}
+
+#[derive(Clone, Copy, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct ShapeMask {
+    pub left: Option<bool>,
+    pub right: Option<bool>,
+    pub top: Option<bool>,
+    pub bottom: Option<bool>,
+    pub below_left: Option<bool>,
+    pub below_right: Option<bool>,
+    pub covered: Option<bool>,
+}
+
+impl ShapeMask {
+    fn parts(&self) -> [Option<bool>; 7] {
+        [
+            self.left,
+            self.right,
+            self.top,
+            self.bottom,
+            self.below_left,
+            self.below_right,
+            self.covered,
+        ]
+    }
+}
+
+impl Mask for ShapeMask {
+    type Hood = Shape;
+
+    fn every() -> Vec<Shape> {
+        (0..1 << 7)
+            .map(|bits: u32| {
+                let bit = |i: u32| bits & 1 << i != 0;
+                Shape {
+                    left: bit(0),
+                    right: bit(1),
+                    top: bit(2),
+                    bottom: bit(3),
+                    below_left: bit(4),
+                    below_right: bit(5),
+                    covered: bit(6),
+                }
+            })
+            .collect()
+    }
+
+    fn matches(&self, n: Shape) -> bool {
+        let values = [
+            n.left,
+            n.right,
+            n.top,
+            n.bottom,
+            n.below_left,
+            n.below_right,
+            n.covered,
+        ];
+        self.parts()
+            .into_iter()
+            .zip(values)
+            .all(|(m, v)| fits(m, v))
+    }
+
+    fn specificity(&self) -> usize {
+        self.parts().iter().filter(|m| m.is_some()).count()
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct SideMask {
+    pub left: Option<bool>,
+    pub right: Option<bool>,
+    pub top: Option<bool>,
+    pub bottom: Option<bool>,
+}
+
+impl Mask for SideMask {
+    type Hood = Sides;
+
+    fn every() -> Vec<Sides> {
+        iproduct!([false, true], [false, true], [false, true], [false, true])
+            .map(|(left, right, top, bottom)| Sides {
+                left,
+                right,
+                top,
+                bottom,
+            })
+            .collect()
+    }
+
+    fn matches(&self, n: Sides) -> bool {
+        fits(self.left, n.left)
+            && fits(self.right, n.right)
+            && fits(self.top, n.top)
+            && fits(self.bottom, n.bottom)
+    }
+
+    fn specificity(&self) -> usize {
+        [self.left, self.right, self.top, self.bottom]
+            .iter()
+            .filter(|m| m.is_some())
+            .count()
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct CornerMask {
+    pub tl: Option<TilingCorner>,
+    pub tr: Option<TilingCorner>,
+    pub bl: Option<TilingCorner>,
+    pub br: Option<TilingCorner>,
+}
+
+impl Mask for CornerMask {
+    type Hood = Corners;
+
+    fn every() -> Vec<Corners> {
+        use TilingCorner::*;
+        let all = [None, Inner, Slope];
+        iproduct!(all, all, all, all)
+            .map(|(tl, tr, bl, br)| Corners { tl, tr, bl, br })
+            .collect()
+    }
+
+    fn matches(&self, n: Corners) -> bool {
+        fits(self.tl, n.tl) && fits(self.tr, n.tr) && fits(self.bl, n.bl) && fits(self.br, n.br)
+    }
+
+    fn specificity(&self) -> usize {
+        [self.tl, self.tr, self.bl, self.br]
+            .iter()
+            .filter(|m| m.is_some())
+            .count()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Rule<M> {
+    pub when: M,
+    pub tile: TerrainTile,
+}
+
+// Ground is first matched against `shapes`, then `sides`, and what none of those take is matched
+// against `corners`. In each list the first rule that matches picks the tile
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct RuleSet {
+    pub shapes: Vec<Rule<ShapeMask>>,
+    pub sides: Vec<Rule<SideMask>>,
+    pub corners: Vec<Rule<CornerMask>>,
+}
+
+fn pick<M: Mask>(rules: &[Rule<M>], n: M::Hood) -> Option<usize> {
+    rules.iter().position(|r| r.when.matches(n))
+}
+
+impl RuleSet {
+    pub fn shape_tile(&self, n: Shape) -> Option<TerrainTile> {
+        pick(&self.shapes, n).map(|i| self.shapes[i].tile)
+    }
+
+    pub fn side_tile(&self, n: Sides) -> Option<TerrainTile> {
+        pick(&self.sides, n).map(|i| self.sides[i].tile)
+    }
+
+    // corners nothing matches are filled in plain
+    pub fn corner_tile(&self, n: Corners) -> TerrainTile {
+        pick(&self.corners, n).map_or(TerrainTile::BlockFace(LMR::M, TMB::M), |i| {
+            self.corners[i].tile
+        })
+    }
+}
+
+// How ground is tiled, as read from `assets/ground.tiling.ron`. A terrain listed under
+// `terrains` is tiled with its own rules instead of the default ones, bar any list it leaves
+// out or empty
+#[derive(Clone, Debug, Deserialize)]
+pub struct TilingRules {
+    pub default: RuleSet,
+    #[serde(default)]
+    pub terrains: HashMap<Terrain, RuleSet>,
+}
+
+impl TilingRules {
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, ron::error::SpannedError> {
+        let mut rules: Self = ron::de::from_bytes(bytes)?;
+        let default = &rules.default;
+        for set in rules.terrains.values_mut() {
+            if set.shapes.is_empty() {
+                set.shapes = default.shapes.clone();
+            }
+            if set.sides.is_empty() {
+                set.sides = default.sides.clone();
+            }
+            if set.corners.is_empty() {
+                set.corners = default.corners.clone();
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn for_terrain(&self, terrain: Terrain) -> &RuleSet {
+        self.terrains.get(&terrain).unwrap_or(&self.default)
+    }
+
+    // Everything in the rules that's likely a mistake, found by trying every neighbourhood
+    pub fn validate(&self) -> Vec<RuleProblem> {
+        let mut sets: Vec<_> = self.terrains.iter().map(|(&t, s)| (Some(t), s)).collect();
+        sets.sort_by_key(|(t, _)| t.map(|t| t as usize));
+        sets.insert(0, (None, &self.default));
+
+        let mut problems = Vec::new();
+        for (terrain, set) in sets {
+            let mut report = |list, kind| {
+                problems.push(RuleProblem {
+                    terrain,
+                    list,
+                    kind,
+                })
+            };
+            check(&set.shapes, false, |k| report("shapes", k));
+            check(&set.sides, false, |k| report("sides", k));
+            check(&set.corners, true, |k| report("corners", k));
+        }
+        problems
+    }
+}
+
+impl Default for TilingRules {
+    fn default() -> Self {
+        Self::from_ron(include_bytes!("../../assets/ground.tiling.ron"))
+            .expect("the bundled tiling rules should parse")
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ProblemKind {
+    // the rule never gets picked, as rules before it take everything it matches
+    Unreachable {
+        rule: usize,
+    },
+    // a rule loses some of what it matches to a vaguer one before it with another tile, which
+    // is seldom what was meant
+    Ambiguous {
+        rule: usize,
+        earlier: usize,
+        example: String,
+    },
+    // neighbourhoods no rule matches
+    Uncovered {
+        count: usize,
+        example: String,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RuleProblem {
+    // `None` for the default rules
+    pub terrain: Option<Terrain>,
+    pub list: &'static str,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for RuleProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.terrain {
+            Some(t) => write!(f, "{:?} {}: ", t, self.list)?,
+            None => write!(f, "default {}: ", self.list)?,
+        }
+        match &self.kind {
+            ProblemKind::Unreachable { rule } => write!(f, "rule {} is never used", rule),
+            ProblemKind::Ambiguous {
+                rule,
+                earlier,
+                example,
+            } => write!(
+                f,
+                "rule {} is more specific than rule {} but loses to it, e.g. at {}",
+                rule, earlier, example
+            ),
+            ProblemKind::Uncovered { count, example } => {
+                write!(
+                    f,
+                    "{} neighbourhoods match no rule, e.g. {}",
+                    count, example
+                )
+            }
+        }
+    }
+}
+
+fn check<M: Mask>(rules: &[Rule<M>], total: bool, mut report: impl FnMut(ProblemKind)) {
+    let mut used = vec![false; rules.len()];
+    let mut ambiguous = Vec::new();
+    let mut uncovered = Vec::new();
+    for n in M::every() {
+        let Some(w) = pick(rules, n) else {
+            uncovered.push(n);
+            continue;
+        };
+        used[w] = true;
+        let specificity = rules[w].when.specificity();
+        for (i, r) in rules.iter().enumerate().skip(w + 1) {
+            if r.when.matches(n)
+                && r.when.specificity() > specificity
+                && r.tile != rules[w].tile
+                && !ambiguous.iter().any(|&(a, b, _)| (a, b) == (i, w))
+            {
+                ambiguous.push((i, w, format!("{:?}", n)));
+            }
+        }
+    }
+
+    for rule in (0..rules.len()).filter(|&i| !used[i]) {
+        report(ProblemKind::Unreachable { rule });
+    }
+    for (rule, earlier, example) in ambiguous {
+        if used[rule] {
+            report(ProblemKind::Ambiguous {
+                rule,
+                earlier,
+                example,
+            });
+        }
+    }
+    if total && let Some(n) = uncovered.first() {
+        report(ProblemKind::Uncovered {
+            count: uncovered.len(),
+            example: format!("{:?}", n),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGE: TerrainTile = TerrainTile::BlockFace(LMR::L, TMB::M);
+    const FILL: TerrainTile = TerrainTile::BlockFace(LMR::M, TMB::M);
+
+    fn problems<M: Mask>(rules: &[Rule<M>], total: bool) -> Vec<ProblemKind> {
+        let mut problems = Vec::new();
+        check(rules, total, |k| problems.push(k));
+        problems
+    }
+
+    #[test]
+    fn rules_after_a_catch_all_are_unreachable() {
+        let rules = [
+            Rule {
+                when: SideMask::default(),
+                tile: FILL,
+            },
+            Rule {
+                when: SideMask {
+                    left: Some(true),
+                    ..Default::default()
+                },
+                tile: EDGE,
+            },
+        ];
+        assert_eq!(
+            problems(&rules, false),
+            vec![ProblemKind::Unreachable { rule: 1 }]
+        );
+    }
+
+    #[test]
+    fn specific_rules_after_vaguer_ones_are_ambiguous() {
+        let rules = [
+            Rule {
+                when: SideMask {
+                    left: Some(true),
+                    ..Default::default()
+                },
+                tile: EDGE,
+            },
+            Rule {
+                when: SideMask {
+                    top: Some(true),
+                    bottom: Some(true),
+                    ..Default::default()
+                },
+                tile: FILL,
+            },
+        ];
+        let problems = problems(&rules, false);
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(
+            problems[0],
+            ProblemKind::Ambiguous {
+                rule: 1,
+                earlier: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn gaps_are_uncovered_only_when_the_list_must_be_total() {
+        let rules = [Rule {
+            when: CornerMask {
+                tl: Some(TilingCorner::None),
+                ..Default::default()
+            },
+            tile: FILL,
+        }];
+        assert!(problems(&rules, false).is_empty());
+        let problems = problems(&rules, true);
+        assert_eq!(problems.len(), 1);
+        // two in three of the 81 corner combinations have something at the top left
+        assert!(matches!(
+            problems[0],
+            ProblemKind::Uncovered { count: 54, .. }
+        ));
+    }
+
+    #[test]
+    fn bundled_rules_are_valid() {
+        assert!(TilingRules::default().validate().is_empty());
+    }
+}
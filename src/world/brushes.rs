@@ -1,10 +1,8 @@
 use bevy::prelude::Resource;
-use enum_iterator::Sequence;
 use itertools::iproduct;
 use itertools::Itertools;
 use itertools::Position;
 use noise::NoiseFn;
-use num_derive::FromPrimitive;
 use rand::thread_rng;
 use rand::Rng;
 use ranges::GenericRange;
@@ -14,7 +12,7 @@ use std::ops::RangeBounds;
 
 use crate::helpers::*;
 
-use super::{feature::*, tile::*, Place};
+use super::{autotile::TilingRules, feature::*, nav::*, pacing::*, tile::*, wfc::wfc_brush, Place};
 
 #[derive(Resource)]
 pub struct Gen {
@@ -22,35 +20,35 @@ pub struct Gen {
     pub zone: noise::ScaleBias<f64, noise::SuperSimplex, 2>,
     pub theme: noise::ScaleBias<f64, noise::Value, 2>,
     pub seed: u32,
+    pub difficulty: DifficultyCurve,
 }
 
-impl Default for Gen {
-    fn default() -> Self {
-        let mut tr = thread_rng();
-        let seed: u32 = tr.gen();
-
+impl Gen {
+    pub fn from_seed(seed: u32) -> Self {
         use noise::MultiFractal;
 
         let octaves_n = 4;
-        let octaves = (seed..)
-            .take(octaves_n)
-            .map(|i| noise::SuperSimplex::new(i))
+        let octaves = (0..octaves_n as u32)
+            .map(|i| noise::SuperSimplex::new(seed.wrapping_add(i)))
             .collect();
         let p = 0.2;
         let terrain = noise::ScaleBias::new(
-            noise::Fbm::new(seed)
-                .set_sources(octaves)
+            // set_octaves rebuilds the sources from the Fbm's own seed, so it has to come first
+            noise::Fbm::new(0)
                 .set_octaves(octaves_n)
+                .set_sources(octaves)
                 .set_frequency(1.0 / 32.0)
                 .set_lacunarity(2.0)
                 .set_persistence(p),
         )
         .set_scale(1.0 / (2.0 * (1.0 + p + p * p + p * p * p)))
         .set_bias(0.5);
-        let zone = noise::ScaleBias::new(noise::SuperSimplex::new(seed + octaves_n as u32))
+        let zone_seed = seed.wrapping_add(octaves_n as u32);
+        let zone = noise::ScaleBias::new(noise::SuperSimplex::new(zone_seed))
             .set_scale(0.5)
             .set_bias(0.5);
-        let theme = noise::ScaleBias::new(noise::Value::new(seed + octaves_n as u32))
+        // distinct from the zone seed so that tile variants don't correlate with zone choice
+        let theme = noise::ScaleBias::new(noise::Value::new(zone_seed.wrapping_add(1)))
             .set_scale(0.5)
             .set_bias(0.5);
 
@@ -59,62 +57,200 @@ impl Default for Gen {
             terrain,
             theme,
             seed,
+            difficulty: DifficultyCurve::default(),
+        }
+    }
+
+    // each band of the level gets noise of its own so that they don't repeat each other, with
+    // the surface keeping the level's seed
+    pub fn for_band(&self, band: i32) -> Self {
+        Self {
+            difficulty: self.difficulty,
+            ..Self::from_seed(self.seed.wrapping_add((band as u32).wrapping_mul(16)))
         }
     }
 }
 
-pub fn generate_level(gen: &Gen) -> Schema {
-    let mut schema = Schema::default();
+// the noise functions aren't Debug, and the seed is enough to tell gens apart
+impl std::fmt::Debug for Gen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Gen")
+            .field("seed", &self.seed)
+            .field("difficulty", &self.difficulty)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Gen {
+    fn default() -> Self {
+        Self::from_seed(thread_rng().gen())
+    }
+}
 
-    let height = 10;
+pub const ZONE_HEIGHT: i32 = 10;
+pub const BAND_HEIGHT: i32 = 2 * ZONE_HEIGHT + 1;
 
-    let size = 1000;
-    let mut x = -size;
-    while x < size {
-        let z = n_to_enum(gen.zone.get([x as f64, 0.0]));
-        let w = gen.zone.get([x as f64, 2.0]) * 10.0 % 1.0;
-        let w = (50.0 * w + 20.0) as i32;
-        let b = Box2::from_box1s(Box1::new(x, x + w), Box1::new(-height, height + 1));
+fn band_of(y: i32) -> i32 {
+    (y + ZONE_HEIGHT).div_euclid(BAND_HEIGHT)
+}
 
-        schema.add(Feature::Zone(z, b));
-        height_map_floor_brush(&mut schema, gen, z, b);
-        match z {
-            Zone::Grass(Zone1::Lake) | Zone::Desert(Zone1::Lake) | Zone::Candy(Zone1::Lake) => {
-                liquid_brush(&mut schema, gen, b, Liquid::Water)
-            }
-            Zone::LavaHills | Zone::LavaPlains => liquid_brush(&mut schema, gen, b, Liquid::Lava),
-            Zone::Mushroom => {
-                big_mushroom_brush(&mut schema, gen, b);
-                mushroom_brush(&mut schema, gen, b);
-            }
-            Zone::Caverns => {
-                cavern_roof_brush(&mut schema, gen, b);
-                cavern_tunnel_brush(&mut schema, gen, b);
-            }
-            Zone::Forest => tree_brush(&mut schema, gen, b, false),
-            Zone::SnowForest => tree_brush(&mut schema, gen, b, true),
-            Zone::Grass(_)
-            | Zone::Desert(_)
-            | Zone::Candy(_)
-            | Zone::StoneMountain
-            | Zone::StoneCliff
-            | Zone::Castle => (),
+const SKY_ZONES: [Zone; 4] = [
+    Zone::Factory(Zone1::Sky),
+    Zone::Grass(Zone1::Sky),
+    Zone::Desert(Zone1::Sky),
+    Zone::Candy(Zone1::Sky),
+];
+// zone noise clusters around the middle, so the most common choices go there
+const UNDERGROUND_ZONES: [Zone; 4] = [
+    Zone::LavaHills,
+    Zone::Caverns,
+    Zone::StoneCliff,
+    Zone::LavaPlains,
+];
+
+// the surface follows the level's pacing, the bands above and below it are free to vary as far
+// as the difficulty curve allows
+fn zone_of(gen: &Gen, x: Box1<i32>, section: Section, band: i32) -> Zone {
+    let n = gen.zone.get([x.lo_incl as f64, 0.0]);
+    let level = gen.difficulty.at(x.lo_incl.abs().min(x.hi_excl.abs()));
+    match Layer::of_band(band) {
+        Layer::Sky => pick_zone(n, SKY_ZONES.iter().copied(), level),
+        Layer::Surface => section.zone,
+        Layer::Underground => pick_zone(n, UNDERGROUND_ZONES.iter().copied(), level),
+    }
+}
+
+// Zones are laid out outwards from x = 0 in the order the pacing grammar gives them, and each
+// band of a zone depends only on its own section and height, so the level comes out the same
+// whatever order the chunks are requested in
+pub fn generate_level(gen: &Gen, level: &mut LevelSchema, box2: Box2<i32>) {
+    let mut covered = level.generated().unwrap_or(Box1::new(0, 0));
+    let mut sections = level.sections();
+    while covered.hi_excl < box2.x.hi_excl {
+        let s = level.section(gen, sections.hi_excl, covered.hi_excl);
+        let b = Box1::new(covered.hi_excl, covered.hi_excl + s.width);
+        level.insert_zone(b, s);
+        covered = covered.union_cover(b);
+        sections.hi_excl += 1;
+    }
+    while covered.lo_incl > box2.x.lo_incl {
+        let s = level.section(gen, sections.lo_incl - 1, covered.lo_incl);
+        let b = Box1::new(covered.lo_incl - s.width, covered.lo_incl);
+        level.insert_zone(b, s);
+        covered = covered.union_cover(b);
+        sections.lo_incl -= 1;
+    }
+
+    let zones: Vec<_> = level.zones(box2.x).collect();
+    for ((x, s), band) in iproduct!(zones, band_of(box2.y.lo_incl)..=band_of(box2.y.hi_excl - 1)) {
+        if !level.has_band(x, band) {
+            let (v, schema) = generate_zone(level.band_gen(gen, band), x, s, band);
+            level.insert_band(v, schema);
         }
-        bonus_brush(&mut schema, gen, b);
-        x += w;
     }
+}
 
-    schema
+// Brushes only see their own band's features, which keeps zones independent of each other. `gen`
+// is the band's own, see `LevelSchema::band_gen`
+fn generate_zone(gen: &Gen, x: Box1<i32>, s: Section, band: i32) -> (VerticalFeature, Schema) {
+    let mut schema = Schema::default();
+
+    let z = zone_of(gen, x, s, band);
+    let base = band * BAND_HEIGHT;
+    let b = Box2::from_box1s(x, Box1::new(base - ZONE_HEIGHT, base + ZONE_HEIGHT + 1));
+
+    schema.add(Feature::Zone(z, b));
+    if z.is_sky() {
+        sky_brush(&mut schema, gen, z, b, s.difficulty);
+    } else if z.info().backend == Backend::Wfc {
+        // if the solver can't find a layout the height map still can
+        if !wfc_brush(&mut schema, gen, z, b) {
+            height_map_floor_brush(&mut schema, gen, z, b, s.difficulty);
+        }
+    } else {
+        height_map_floor_brush(&mut schema, gen, z, b, s.difficulty);
+    }
+    if z.info().terrain == Terrain::Snow {
+        igloo_brush(&mut schema, gen, b);
+    }
+    match z {
+        Zone::Grass(Zone1::Lake)
+        | Zone::Desert(Zone1::Lake)
+        | Zone::Candy(Zone1::Lake)
+        | Zone::Factory(Zone1::Lake)
+        | Zone::LavaHills
+        | Zone::LavaPlains => liquid_brush(&mut schema, gen, b, liquid_of(z), s.difficulty),
+        Zone::Mushroom => big_mushroom_brush(&mut schema, gen, b),
+        Zone::Caverns => {
+            cavern_roof_brush(&mut schema, gen, b, s.difficulty);
+            cavern_tunnel_brush(&mut schema, gen, b);
+        }
+        Zone::Forest => tree_brush(&mut schema, gen, b, false),
+        Zone::SnowForest => {
+            liquid_brush(&mut schema, gen, b, liquid_of(z), s.difficulty);
+            tree_brush(&mut schema, gen, b, true);
+        }
+        Zone::Castle => castle_brush(&mut schema, gen, b),
+        Zone::Grass(_)
+        | Zone::Desert(_)
+        | Zone::Candy(_)
+        | Zone::Factory(_)
+        | Zone::StoneMountain
+        | Zone::StoneCliff => (),
+    }
+    if let Zone::Candy(_) = z {
+        candy_brush(&mut schema, gen, b);
+    }
+    if let Zone::Factory(_) = z {
+        factory_brush(&mut schema, gen, b);
+    }
+    if let Zone::Grass(Zone1::Plains) | Zone::Desert(Zone1::Plains) = z {
+        village_brush(&mut schema, gen, z, b);
+    }
+    repair_brush(&mut schema, gen, z, b);
+    clutter_brush(&mut schema, gen, z, b);
+    bonus_brush(&mut schema, gen, b);
+
+    (VerticalFeature { band, box2: b }, schema)
+}
+
+const TRANSITION: i32 = 6;
+// chance that a run of ground is laid in the zone's alternative terrain, where it has one
+const ALT_TERRAIN_CHANCE: f64 = 0.3;
+// the widest a gap in the ground gets, at full difficulty
+const MAX_GAP: i32 = 5;
+
+// 0 on a zone's edge columns, rising to 1 across the transition strip
+fn edge_blend(x: Box1<i32>, i: i32) -> f64 {
+    let d = (i - x.lo_incl).min(x.hi_excl - 1 - i);
+    (d as f64 / TRANSITION as f64).clamp(0.0, 1.0)
 }
 
-fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Box2<i32>) {
-    let runs = region
+// The heights themselves come from noise shared by every zone, so neighbouring zones only need
+// to agree on what happens at their boundary: towards the edges gaps fade out and steps turn
+// into slopes, and the step across the boundary is always sloped by the zone on its right
+fn height_map_floor_brush(
+    schema: &mut Schema,
+    gen: &Gen,
+    zone: Zone,
+    region: Box2<i32>,
+    difficulty: f64,
+) {
+    let height_at = |i: i32| n_to_box1(gen.terrain.get([i as f64, 0.0]), region.y);
+    // each run of level ground is laid in one terrain or the other
+    let terrain_at = |i: i32| match zone.info().alt_terrain {
+        Some(alt) if gen.theme.get([i as f64, 74.0]) < ALT_TERRAIN_CHANCE => alt,
+        _ => zone.info().terrain,
+    };
+    let max_gap = 1 + (difficulty * (MAX_GAP - 1) as f64).round() as i32;
+    let mut runs: Vec<_> = region
         .x
         .iter()
-        .map(|i| {
-            let height = n_to_box1(gen.terrain.get([i as f64, 0.0]), region.y);
-            let gap = gen.zone.get([i as f64, 0.0]) < zone.info().gap_chance;
-            (1, i, if gap { region.y.lo_incl } else { height })
+        .scan(0, |gap_run, i| {
+            let gap_chance = zone.info().gap_chance * (0.5 + difficulty) * edge_blend(region.x, i);
+            let gap = gen.zone.get([i as f64, 0.0]) < gap_chance && *gap_run < max_gap;
+            *gap_run = if gap { *gap_run + 1 } else { 0 };
+            Some((1, i, if gap { region.y.lo_incl } else { height_at(i) }))
         })
         .coalesce(|(l1, x1, a), (l2, x2, b)| {
             if a == b {
@@ -122,9 +258,55 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
             } else {
                 Err(((l1, x1, a), (l2, x2, b)))
             }
+        })
+        .collect();
+
+    // edge columns are never gaps, so the neighbour's last height is known without generating it
+    let (l, x, h) = runs[0];
+    let h_prev = height_at(x - 1);
+    // a run too short for the whole step still slopes as far as it goes, leaving a smaller step
+    // up or down to the next run
+    let step = (h - h_prev).abs().min(l);
+    if step > 0 && h.min(h_prev) > region.y.lo_incl {
+        let (lr, height) = if h_prev < h {
+            (LR::L, Box1::new(h_prev, h_prev + step))
+        } else {
+            (LR::R, Box1::new(h_prev - step, h_prev))
+        };
+        schema.add(Feature::HillBlock {
+            terrain: terrain_at(x),
+            start_x: x,
+            height,
+            bridge_thickness: None,
+            lr,
         });
+        schema.add(Feature::GroundBlock(
+            GroundCover::TopCovered,
+            terrain_at(x),
+            Box2 {
+                x: Box1::new(x, x + step),
+                y: Box1::new(region.y.lo_incl, height.lo_incl),
+            },
+        ));
+        if step == l {
+            runs.remove(0);
+        } else {
+            runs[0] = (l - step, x + step, h);
+        }
+    }
 
-    for pos in runs.tuple_windows().with_position() {
+    if let [(l, x, h)] = runs[..] && h > region.y.lo_incl {
+        schema.add(Feature::GroundBlock(
+            GroundCover::TopCovered,
+            terrain_at(x),
+            Box2 {
+                x: Box1::new(x, x + l),
+                y: Box1::new(region.y.lo_incl, h),
+            },
+        ));
+    }
+
+    for pos in runs.into_iter().tuple_windows().with_position() {
         let ((l1, x1, h1), (l2, x2, h2)) = pos.into_inner();
         assert!(h1 != h2);
         assert!(l1 > 0 && l2 > 0);
@@ -132,7 +314,11 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
         assert!(x1 + l1 == x2);
         assert!(h1 >= region.y.lo_incl && h2 >= region.y.lo_incl);
 
-        let is_hill = gen.zone.get([x1 as f64, 1.0]) < zone.info().hill_chance;
+        let (t1, t2) = (terrain_at(x1), terrain_at(x2));
+
+        let blend = edge_blend(region.x, x1).min(edge_blend(region.x, x2));
+        let hill_chance = 1.0 - blend * (1.0 - zone.info().hill_chance);
+        let is_hill = gen.zone.get([x1 as f64, 1.0]) < hill_chance;
 
         let run_length = (h2 - h1).abs();
         let x_run = n_to_fitted_box1(
@@ -157,7 +343,7 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
                 (LR::R, Box1::new(h2, h1))
             };
             schema.add(Feature::HillBlock {
-                terrain: zone.info().terrain,
+                terrain: t1,
                 start_x: x_run.lo_incl,
                 height,
                 bridge_thickness,
@@ -167,7 +353,7 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
                 assert!(h1 > region.y.lo_incl);
                 schema.add(Feature::GroundBlock(
                     GroundCover::TopCovered,
-                    zone.info().terrain,
+                    t1,
                     Box2 {
                         x: Box1::new(x1, x_run.lo_incl),
                         y: Box1::new(region.y.lo_incl, h1),
@@ -177,7 +363,7 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
             if x_run.hi_excl < x2 && h2 > region.y.lo_incl {
                 schema.add(Feature::GroundBlock(
                     GroundCover::TopCovered,
-                    zone.info().terrain,
+                    t1,
                     Box2 {
                         x: Box1::new(x_run.hi_excl, x2),
                         y: Box1::new(region.y.lo_incl, h2),
@@ -187,7 +373,7 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
             if bridge_thickness.is_none() && h2 > region.y.lo_incl {
                 schema.add(Feature::GroundBlock(
                     GroundCover::TopCovered,
-                    zone.info().terrain,
+                    t1,
                     Box2 {
                         x: x_run,
                         y: Box1::new(region.y.lo_incl, h1.min(h2)),
@@ -197,7 +383,7 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
         } else if h1 > region.y.lo_incl {
             schema.add(Feature::GroundBlock(
                 GroundCover::TopCovered,
-                zone.info().terrain,
+                t1,
                 Box2 {
                     x: Box1::new(x1, x1 + l1),
                     y: Box1::new(region.y.lo_incl, h1),
@@ -205,10 +391,10 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
             ));
         }
 
-        if let Position::Last(_) = pos && h2 > region.y.lo_incl {
+        if let Position::Last(_) | Position::Only(_) = pos && h2 > region.y.lo_incl {
             schema.add(Feature::GroundBlock(
                 GroundCover::TopCovered,
-                zone.info().terrain,
+                t2,
                 Box2 {
                     x: Box1::new(x2, x2 + l2),
                     y: Box1::new(region.y.lo_incl, h2),
@@ -218,13 +404,18 @@ fn height_map_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, region: Bo
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
-enum Liquid {
-    Water,
-    Lava,
+fn liquid_of(zone: Zone) -> Liquid {
+    match zone {
+        Zone::LavaHills | Zone::LavaPlains => Liquid::Lava,
+        Zone::Candy(_) => Liquid::SparklingWater,
+        _ if zone.info().terrain == Terrain::Snow => Liquid::IceWater,
+        _ => Liquid::Water,
+    }
 }
 
-fn liquid_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>, liquid: Liquid) {
+// Fills the dips and gaps in the zone up to a level no higher than its lowest ground, a body per
+// stretch of open surface so that a pool one column across gets drawn as one
+fn liquid_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>, liquid: Liquid, difficulty: f64) {
     let mut height = box2.y.hi_excl;
     for f in schema.intersecting(box2) {
         match f {
@@ -238,17 +429,54 @@ fn liquid_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>, liquid: Liquid)
             _ => (),
         }
     }
-    let height = n_to_box1(
-        gen.terrain.get([box2.x.lo_incl as f64, 0.0]),
-        Box1::new(box2.y.lo_incl + 1, height + 2),
-    );
-    let b = Box2::from_box1s(box2.x, Box1::new(box2.y.lo_incl, height));
-    match liquid {
-        Liquid::Water => schema.add(Feature::SurfaceWater(b)),
-        Liquid::Lava => schema.add(Feature::SurfaceLava(b)),
+    // lava only rises to the top of its range on the hardest stretches
+    let n = gen.terrain.get([box2.x.lo_incl as f64, 0.0]);
+    let n = match liquid {
+        Liquid::Lava => n * (0.5 + difficulty).min(1.0),
+        _ => n,
+    };
+    let height = n_to_box1(n, Box1::new(box2.y.lo_incl + 1, height + 2));
+    let open: Vec<_> = box2
+        .x
+        .iter()
+        .filter(|&x| is_clear(schema, Place::new(x, height - 1)))
+        .map(Box1::from_point)
+        .coalesce(|a, b| {
+            if a.hi_excl == b.lo_incl {
+                Ok(a.union_cover(b))
+            } else {
+                Err((a, b))
+            }
+        })
+        .collect();
+    for x in open {
+        schema.add(Feature::SurfaceLiquid(
+            liquid,
+            Box2::from_box1s(x, Box1::new(box2.y.lo_incl, height)),
+        ));
     }
 }
 
+fn box1s_of(ranges: &Ranges<i32>) -> Vec<Box1<i32>> {
+    ranges
+        .as_slice()
+        .iter()
+        .map(|gr| {
+            let start = match gr.start_bound() {
+                Bound::Included(x) => *x,
+                Bound::Excluded(x) => *x + 1,
+                Bound::Unbounded => panic!("shouldn't be an unbounded bound"),
+            };
+            let end = match gr.end_bound() {
+                Bound::Included(x) => *x + 1,
+                Bound::Excluded(x) => *x,
+                Bound::Unbounded => panic!("shouldn't be an unbounded bound"),
+            };
+            Box1::new(start, end)
+        })
+        .collect()
+}
+
 fn big_mushroom_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
     let mut open_ranges = Ranges::new();
     open_ranges.insert(GenericRange::new_closed_open(
@@ -270,23 +498,7 @@ fn big_mushroom_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
         }
     }
 
-    let mut ranges = open_ranges
-        .as_slice()
-        .iter()
-        .map(|&gr| {
-            let start = match gr.start_bound() {
-                Bound::Included(x) => *x,
-                Bound::Excluded(x) => *x + 1,
-                Bound::Unbounded => panic!("shouldn't be an unbounded bound"),
-            };
-            let end = match gr.end_bound() {
-                Bound::Included(x) => *x + 1,
-                Bound::Excluded(x) => *x,
-                Bound::Unbounded => panic!("shouldn't be an unbounded bound"),
-            };
-            Box1::new(start, end)
-        })
-        .collect::<Vec<_>>();
+    let mut ranges = box1s_of(&open_ranges);
     ranges.sort_by(|r1, r2| r1.size().cmp(&r2.size()).reverse());
 
     // TERMINATION: loops over an ever-shrinking set of ranges
@@ -318,45 +530,877 @@ fn big_mushroom_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
     }
 }
 
-fn mushroom_brush(_schema: &mut Schema, _gen: &Gen, _box2: Box2<i32>) {
-    // todo
+// rows kept free between the ground and anything hanging over it
+const HEADROOM: i32 = 3;
+
+fn ground_top(schema: &Schema, x: i32, box2: Box2<i32>) -> i32 {
+    schema
+        .intersecting(Box2::from_box1s(Box1::from_point(x), box2.y))
+        .filter_map(|f| match f {
+            Feature::GroundBlock(..) | Feature::HillBlock { .. } => Some(f.bounds().y.hi_excl),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(box2.y.lo_incl)
+}
+
+fn hill_top(start_x: i32, height: Box1<i32>, lr: LR, x: i32) -> i32 {
+    match lr {
+        LR::L => height.lo_incl + (x - start_x),
+        LR::R => height.hi_excl - 1 - (x - start_x),
+    }
+}
+
+// whether p is free for something to be put in, looking past the bounding boxes of hills
+fn is_clear(schema: &Schema, p: Place) -> bool {
+    schema
+        .intersecting(Box2::from_point(p.into()))
+        .all(|f| match f {
+            Feature::HillBlock {
+                start_x,
+                height,
+                bridge_thickness,
+                lr,
+                ..
+            } => {
+                let top = hill_top(start_x, height, lr, p.x);
+                p.y > top || bridge_thickness.map_or(false, |t| p.y < top - t as i32)
+            }
+            Feature::Zone(..)
+            | Feature::FlatGround(..)
+            | Feature::SlopedGround { .. }
+            | Feature::CaveTunnel { .. } => true,
+            _ => false,
+        })
+}
+
+// The flat stretches of ground in `box2`, as the surface at their left end and the columns they
+// span. Ground under a hill slope is still reported as flat, so anything put on one has to be
+// checked with `fits_clear`
+fn flat_spots(schema: &Schema, box2: Box2<i32>) -> Vec<(Place, Box1<i32>)> {
+    schema
+        .intersecting(box2)
+        .filter_map(|f| match f {
+            Feature::FlatGround(p, width) => Some((p, Box1::new(p.x, p.x + width as i32))),
+            _ => None,
+        })
+        .collect()
+}
+
+// whether `b` lies in `box2` with nothing in the way
+fn fits_clear(schema: &Schema, box2: Box2<i32>, b: Box2<i32>) -> bool {
+    box2.contains_box(b)
+        && iproduct!(b.x.iter(), b.y.iter()).all(|(x, y)| is_clear(schema, Place::new(x, y)))
+}
+
+// Walks each flat in `box2` left to right, trying the prop `prop` gives for a column and the
+// ground row over it with a chance of `density`, drawn from noise row `row`. Props that fit are
+// kept with a column free after each
+fn flat_props_brush(
+    schema: &mut Schema,
+    gen: &Gen,
+    box2: Box2<i32>,
+    row: f64,
+    density: f64,
+    prop: impl Fn(i32, i32) -> Feature,
+) {
+    for (p, flat) in flat_spots(schema, box2) {
+        let mut x = flat.lo_incl;
+        while x < flat.hi_excl {
+            if gen.theme.get([x as f64, row]) >= density {
+                x += 1;
+                continue;
+            }
+            let f = prop(x, p.y + 1);
+            let b = f.bounds();
+            if !flat.contains_box(b.x) || !fits_clear(schema, box2, b) {
+                x += 1;
+                continue;
+            }
+            schema.add(f);
+            x = b.x.hi_excl + 1;
+        }
+    }
+}
+
+fn cave_of(gen: &Gen, box2: Box2<i32>) -> Cave {
+    if gen.theme.get([box2.x.lo_incl as f64, 10.0]) < 0.7 {
+        Cave::Stone
+    } else {
+        Cave::Dirt
+    }
 }
 
-fn cavern_roof_brush(_schema: &mut Schema, _gen: &Gen, _box2: Box2<i32>) {
-    // todo
+fn cavern_roof_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>, difficulty: f64) {
+    let cave = cave_of(gen, box2);
+    // the harder the stretch, the more of the spikes are there
+    let spikes = (30.0 * (0.5 + difficulty)).round() as u32;
+    let runs = box2
+        .x
+        .iter()
+        .map(|i| {
+            // one row for the jagged edge and one for the stalactites under it
+            let thickness = n_to_box1(gen.terrain.get([i as f64, 11.0]), Box1::new(3, 7));
+            let room = box2.y.hi_excl - ground_top(schema, i, box2) - HEADROOM;
+            (1, i, if room < 3 { 0 } else { thickness.min(room) })
+        })
+        .coalesce(|(l1, x1, a), (l2, x2, b)| {
+            if a == b {
+                Ok((l1 + l2, x1, a))
+            } else {
+                Err(((l1, x1, a), (l2, x2, b)))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for (l, x, thickness) in runs {
+        if thickness > 0 {
+            schema.add(Feature::CaveCeiling {
+                box2: Box2::from_box1s(
+                    Box1::new(x, x + l),
+                    Box1::new(box2.y.hi_excl - thickness, box2.y.hi_excl),
+                ),
+                cave,
+                spikes,
+            });
+        }
+    }
 }
 
-fn cavern_tunnel_brush(_schema: &mut Schema, _gen: &Gen, _box2: Box2<i32>) {
-    // todo
+fn cavern_tunnel_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    let cave = cave_of(gen, box2);
+    let mut blocks = schema
+        .intersecting(box2)
+        .filter_map(|f| match f {
+            Feature::GroundBlock(gc, terrain, b) => Some((gc, terrain, b)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    blocks.sort_by_key(|(_, _, b)| (b.x.lo_incl, b.y.lo_incl));
+
+    for (gc, terrain, b) in blocks {
+        let tunnel_height = n_to_box1(gen.theme.get([b.x.lo_incl as f64, 12.0]), Box1::new(2, 4));
+        // at least a row of floor and a row of roof
+        if b.x.size() < 4 || b.y.size() < tunnel_height + 2 {
+            continue;
+        }
+        if gen.theme.get([b.x.lo_incl as f64, 13.0]) < 0.5 {
+            continue;
+        }
+
+        let floor = n_to_box1(
+            gen.theme.get([b.x.lo_incl as f64, 14.0]),
+            Box1::new(b.y.lo_incl + 1, b.y.hi_excl - tunnel_height),
+        );
+        let tunnel = Box2::from_box1s(b.x, Box1::new(floor, floor + tunnel_height));
+
+        // only carve tunnels that can be walked into from at least one side
+        let open = [b.x.lo_incl - 1, b.x.hi_excl]
+            .into_iter()
+            .filter(|&x| box2.x.contains(x))
+            .any(|x| ground_top(schema, x, box2) <= floor);
+        if !open {
+            continue;
+        }
+
+        schema.remove(Feature::GroundBlock(gc, terrain, b));
+        schema.add(Feature::GroundBlock(
+            gc,
+            terrain,
+            Box2::from_box1s(b.x, Box1::new(b.y.lo_incl, tunnel.y.lo_incl)),
+        ));
+        schema.add(Feature::GroundBlock(
+            gc,
+            terrain,
+            Box2::from_box1s(b.x, Box1::new(tunnel.y.hi_excl, b.y.hi_excl)),
+        ));
+        schema.add(Feature::CaveTunnel { box2: tunnel, cave });
+    }
 }
 
-fn tree_brush(_schema: &mut Schema, _gen: &Gen, _box2: Box2<i32>, _snow: bool) {
-    // todo
+const IGLOO_CHANCE: f64 = 0.3;
+
+fn igloo_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    for (p, flat) in flat_spots(schema, box2) {
+        if gen.theme.get([p.x as f64, 25.0]) >= IGLOO_CHANCE {
+            continue;
+        }
+        let width = n_to_box1(gen.theme.get([p.x as f64, 26.0]), Box1::new(3, 6));
+        let height = n_to_box1(gen.theme.get([p.x as f64, 27.0]), Box1::new(2, 4));
+        if let Some(x) = n_to_fitted_box1(gen.theme.get([p.x as f64, 28.0]), width, flat) {
+            let igloo = Box2::from_box1s(x, Box1::new(p.y + 1, p.y + 1 + height));
+            if !fits_clear(schema, box2, igloo) {
+                continue;
+            }
+
+            // the door is in one of the side walls, so it can be walked through
+            let door = if n_to_bool(gen.theme.get([p.x as f64, 29.0])) {
+                0
+            } else {
+                width - 1
+            };
+            schema.add(Feature::Igloo {
+                box2: igloo,
+                door: door as u32,
+            });
+        }
+    }
 }
 
-fn bonus_brush(_schema: &mut Schema, _gen: &Gen, _box2: Box2<i32>) {
-    // todo
+// chance that a free spot on flat ground gets a tree
+const TREE_DENSITY: f64 = 0.35;
+
+fn tree_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>, snow: bool) {
+    let mut flats = Vec::new();
+    let mut slopes = Vec::new();
+    for f in schema.intersecting(box2) {
+        match f {
+            Feature::FlatGround(p, width) => flats.push((p, width)),
+            Feature::SlopedGround { start, height } => {
+                slopes.push(Box1::new(start.x, start.x + height.abs()))
+            }
+            _ => (),
+        }
+    }
+    flats.sort_by_key(|(p, _)| (p.x, p.y));
+
+    // trees are three tiles wide, centred on x, with at least a tile between them
+    let mut next_x = box2.x.lo_incl + 1;
+    for (p, width) in flats {
+        // see `flat_spots`, the slopes are cut out here rather than checked for
+        let mut open_ranges = Ranges::new();
+        open_ranges.insert(GenericRange::new_closed_open(p.x, p.x + width as i32));
+        for s in slopes.iter() {
+            open_ranges.remove(GenericRange::new_closed_open(s.lo_incl, s.hi_excl));
+        }
+
+        let base_y = p.y + 1;
+        let room = box2.y.hi_excl - base_y;
+        for b in box1s_of(&open_ranges) {
+            let mut x = next_x.max(b.lo_incl + 1);
+            while x + 1 < b.hi_excl {
+                if gen.theme.get([x as f64, 7.0]) >= TREE_DENSITY {
+                    x += 1;
+                    continue;
+                }
+
+                let pine = gen.theme.get([x as f64, 8.0]) < if snow { 0.75 } else { 0.5 };
+                let heights = if pine {
+                    Box1::new(4, 8)
+                } else {
+                    Box1::new(4, 7)
+                };
+                let height = n_to_box1(gen.theme.get([x as f64, 9.0]), heights).min(room);
+                if height < 3
+                    || !iproduct!(x - 1..x + 2, base_y..base_y + height)
+                        .all(|(x, y)| is_clear(schema, Place::new(x, y)))
+                {
+                    x += 1;
+                    continue;
+                }
+
+                let base = Place::new(x, base_y);
+                let height = height as u32;
+                if pine {
+                    schema.add(Feature::PineTree { base, height, snow });
+                } else {
+                    schema.add(Feature::BroadleafTree { base, height, snow });
+                }
+                x += 4;
+                next_x = x;
+            }
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct LayeredTile {
-    background: TilingTile,
-    midground: TilingTile,
-    foreground: TilingTile,
+const CASTLE_CHANCE: f64 = 0.6;
+const TOWER_WIDTH: i32 = 3;
+
+fn castle_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    for (p, flat) in flat_spots(schema, box2) {
+        if gen.theme.get([p.x as f64, 30.0]) >= CASTLE_CHANCE {
+            continue;
+        }
+        let ground = p.y + 1;
+        let wall_height = n_to_box1(gen.theme.get([p.x as f64, 31.0]), Box1::new(3, 6));
+        let tower_height =
+            wall_height + n_to_box1(gen.theme.get([p.x as f64, 32.0]), Box1::new(3, 5));
+        let tower = |x: i32| {
+            Feature::CastleTower(Box2::from_box1s(
+                Box1::new(x, x + TOWER_WIDTH),
+                Box1::new(ground, ground + tower_height),
+            ))
+        };
+
+        let keep_or_wall = |x: Box1<i32>| {
+            if gen.theme.get([p.x as f64, 33.0]) < 0.4 {
+                Some(Feature::CastleKeep(Box2::from_box1s(
+                    x,
+                    Box1::new(ground, ground + wall_height + 1),
+                )))
+            } else {
+                let gate = n_to_fitted_box1(gen.theme.get([p.x as f64, 34.0]), 3, x)?;
+                Some(Feature::CastleWall {
+                    box2: Box2::from_box1s(x, Box1::new(ground, ground + wall_height)),
+                    gate: gate.lo_incl,
+                })
+            }
+        };
+
+        // a tower at each end with a keep or a curtain wall between them, if there's room
+        let mut castle = Vec::new();
+        if flat.size() >= 2 * TOWER_WIDTH + 3 {
+            castle.push(tower(flat.lo_incl));
+            castle.push(tower(flat.hi_excl - TOWER_WIDTH));
+            castle.extend(keep_or_wall(Box1::new(
+                flat.lo_incl + TOWER_WIDTH,
+                flat.hi_excl - TOWER_WIDTH,
+            )));
+        } else if flat.size() >= 5 {
+            castle.extend(keep_or_wall(flat));
+        } else if let Some(x) =
+            n_to_fitted_box1(gen.theme.get([p.x as f64, 35.0]), TOWER_WIDTH, flat)
+        {
+            castle.push(tower(x.lo_incl));
+        }
+
+        if castle.iter().all(|f| fits_clear(schema, box2, f.bounds())) {
+            for f in castle {
+                schema.add(f);
+            }
+        }
+    }
 }
 
-fn get_tile(schema: &Schema, gen: &Gen, p: Place) -> LayeredTile {
-    let mut t = LayeredTile {
-        background: TilingTile::Exactly(Tile::Air),
-        midground: TilingTile::Exactly(Tile::Air),
-        foreground: TilingTile::Exactly(Tile::Air),
+const SKY_GAP: i32 = 4;
+const SKY_RISE: i32 = DESIGN_JUMP_HEIGHT - 2;
+const EDGE_ISLAND: i32 = 3;
+
+// Islands and half-tile platforms hopped across from left to right, each jump at most SKY_GAP
+// across and SKY_RISE up, with wider platforms and shorter jumps the easier the zone is. The
+// islands on the zone's edges sit at the shared terrain height so that neighbouring zones line
+// up, and the walk is steered so the last one can be reached
+fn sky_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>, difficulty: f64) {
+    let terrain = zone.info().terrain;
+    let lowest = box2.y.lo_incl + 3;
+    let highest = box2.y.hi_excl - HEADROOM;
+    let top_at =
+        |i: i32| n_to_box1(gen.terrain.get([i as f64, 0.0]), box2.y).clamp(lowest, highest);
+    let island = |x: Box1<i32>, top: i32, depth: i32| Feature::SkyIsland {
+        terrain,
+        box2: Box2::from_box1s(x, Box1::new(top - 1 - depth, top)),
     };
 
+    let first = Box1::new(box2.x.lo_incl, box2.x.lo_incl + EDGE_ISLAND);
+    let last = Box1::new(box2.x.hi_excl - 1 - EDGE_ISLAND, box2.x.hi_excl - 1);
+    let target = top_at(box2.x.hi_excl - 1);
+    let gaps = Box1::new(1, 2 + (difficulty * (SKY_GAP - 1) as f64).round() as i32);
+    let shortest = 1 + ((1.0 - difficulty) * 3.0).round() as i32;
+    let widths = Box1::new(shortest, shortest + 5);
+    let mut top = top_at(first.lo_incl);
+    schema.add(island(first, top, 1));
+
+    let mut x = first.hi_excl;
+    loop {
+        let room = last.lo_incl - x;
+        if room <= SKY_GAP {
+            break;
+        }
+        let gap = n_to_box1(gen.theme.get([x as f64, 49.0]), gaps);
+        let gap = gap.min(room - 2);
+        let width = n_to_box1(gen.theme.get([x as f64, 50.0]), widths);
+        let width = width.min(room - gap - 1);
+        let piece = Box1::new(x + gap, x + gap + width);
+
+        // enough rises have to be left to climb to the last island
+        let furthest = gaps.hi_excl - 1 + widths.hi_excl - 1;
+        let jumps_left = 1 + (last.lo_incl - piece.hi_excl - SKY_GAP).max(0) / furthest;
+        let dy = n_to_box1(
+            gen.theme.get([x as f64, 51.0]),
+            Box1::new(-SKY_RISE - 1, SKY_RISE + 1),
+        );
+        top = (top + dy)
+            .max(target - SKY_RISE * jumps_left)
+            .min(top + SKY_RISE)
+            .clamp(lowest, highest);
+
+        if width >= 2 && gen.theme.get([x as f64, 52.0]) < 0.5 {
+            let depth = if width == 2 {
+                0
+            } else {
+                n_to_box1(gen.theme.get([x as f64, 53.0]), Box1::new(1, 3))
+            };
+            schema.add(island(piece, top, depth));
+        } else {
+            let alt = n_to_bool(gen.theme.get([x as f64, 54.0]));
+            for i in piece.iter() {
+                let tt = if width == 1 {
+                    TerrainTile::SingleHalf(alt)
+                } else {
+                    TerrainTile::Half(alt, lmr_of(piece, i))
+                };
+                schema.add(Feature::Tile(
+                    Place::new(i, top - 1),
+                    Tile::Terrain(terrain, tt),
+                ));
+            }
+        }
+        x = piece.hi_excl;
+    }
+
+    schema.add(island(last, target, 1));
+}
+
+const MAX_REPAIRS: u32 = 16;
+
+// Makes sure a band can be crossed from its leftmost ground to its rightmost. A break on the
+// level is bridged, anything else gets a stepping stone as far over it as a jump reaches, until
+// the way is clear. A band that can't be mended that way is laid flat instead. Only the band's
+// own features are looked at, so this doesn't depend on what's been generated around it
+fn repair_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>) {
+    for repairs in 0..=MAX_REPAIRS {
+        let nav = NavGraph::new(schema.intersecting(box2));
+        let Some((from, to)) = nav.ends() else { return };
+        let Err((f, t)) = nav.check(from, to) else {
+            return;
+        };
+        if repairs == MAX_REPAIRS {
+            break;
+        }
+
+        let gap = Box1::new(f.x + 1, t.x.max(f.x + 1));
+        if f.y == t.y && gap.iter().all(|x| is_clear(schema, Place::new(x, f.y))) {
+            for x in gap.iter() {
+                schema.add(Feature::Tile(Place::new(x, f.y), Tile::Bridge));
+            }
+            continue;
+        }
+
+        // the stone needs room over it to be stood on. Failing anywhere to go on the way over, it
+        // goes behind so the climb can zigzag, and failing that it raises the floor of the pit
+        let y = f.y + (t.y - f.y).clamp(-DESIGN_JUMP_HEIGHT, DESIGN_JUMP_HEIGHT - 1);
+        let open = |p: Place| {
+            can_jump(p.x - f.x, p.y - f.y)
+                && box2.contains((p.x, p.y))
+                && box2.contains((p.x, p.y + 2))
+                && (p.y..p.y + 3).all(|y| is_clear(schema, Place::new(p.x, y)))
+        };
+        let Some(p) = gap
+            .iter()
+            .rev()
+            .chain((1..=DESIGN_JUMP_APEX).map(|dx| f.x - dx))
+            .map(|x| Place::new(x, y))
+            .chain([f + Place::new(0, 1)])
+            .find(|&p| open(p))
+        else {
+            break;
+        };
+        schema.add(Feature::Tile(
+            p,
+            Tile::Terrain(
+                zone.info().terrain,
+                TerrainTile::SingleHalf(n_to_bool(gen.theme.get([p.x as f64, 72.0]))),
+            ),
+        ));
+    }
+
+    flat_floor_brush(schema, gen, zone, box2);
+}
+
+// Clears the band down to bare ground, level with the height map's at the left edge and sloping
+// to meet it at the right edge, the same as the neighbouring zones expect
+fn flat_floor_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>) {
+    let cleared: Vec<_> = schema
+        .intersecting(box2)
+        .filter(|f| !matches!(f, Feature::Zone(..)))
+        .collect();
+    for f in cleared {
+        schema.remove(f);
+    }
+
+    let terrain = zone.info().terrain;
+    let height_at =
+        |i: i32| n_to_box1(gen.terrain.get([i as f64, 0.0]), box2.y).max(box2.y.lo_incl + 1);
+    let (h0, h1) = (height_at(box2.x.lo_incl - 1), height_at(box2.x.hi_excl - 1));
+    let step = (h1 - h0).abs().min(box2.x.size());
+    let slope = Box1::new(box2.x.hi_excl - step, box2.x.hi_excl);
+    if slope.lo_incl > box2.x.lo_incl {
+        schema.add(Feature::GroundBlock(
+            GroundCover::TopCovered,
+            terrain,
+            Box2::from_box1s(
+                Box1::new(box2.x.lo_incl, slope.lo_incl),
+                Box1::new(box2.y.lo_incl, h0),
+            ),
+        ));
+    }
+    if step > 0 {
+        let (lr, height) = if h0 < h1 {
+            (LR::L, Box1::new(h0, h0 + step))
+        } else {
+            (LR::R, Box1::new(h0 - step, h0))
+        };
+        schema.add(Feature::HillBlock {
+            terrain,
+            start_x: slope.lo_incl,
+            height,
+            bridge_thickness: None,
+            lr,
+        });
+        schema.add(Feature::GroundBlock(
+            GroundCover::TopCovered,
+            terrain,
+            Box2::from_box1s(slope, Box1::new(box2.y.lo_incl, height.lo_incl)),
+        ));
+    }
+}
+
+// chance that a free spot on flat ground gets a candy prop
+const CANDY_DENSITY: f64 = 0.3;
+
+fn candy_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    flat_props_brush(schema, gen, box2, 38.0, CANDY_DENSITY, |x, ground| {
+        let prop = n_to_enum(gen.theme.get([x as f64, 39.0]));
+        let n = gen.theme.get([x as f64, 40.0]);
+        // the solid ones stay low enough to be jumped onto
+        let (width, height) = match prop {
+            CandyProp::Lollipop => (1, n_to_box1(n, Box1::new(2, DESIGN_JUMP_HEIGHT + 1))),
+            CandyProp::CandyCane => (2, n_to_box1(n, Box1::new(2, 6))),
+            CandyProp::CandyPole => (1, n_to_box1(n, Box1::new(2, DESIGN_JUMP_HEIGHT + 1))),
+            CandyProp::IceCream => (1, 2),
+            CandyProp::GummyWorm => (n_to_box1(n, Box1::new(3, 7)), 1),
+        };
+        Feature::Candy {
+            prop,
+            box2: Box2::from_box1s(Box1::new(x, x + width), Box1::new(ground, ground + height)),
+        }
+    });
+}
+
+// chance that a free spot on flat ground gets a factory prop
+const FACTORY_DENSITY: f64 = 0.35;
+
+fn factory_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    flat_props_brush(schema, gen, box2, 43.0, FACTORY_DENSITY, |x, ground| {
+        let prop = n_to_enum(gen.theme.get([x as f64, 44.0]));
+        let n = gen.theme.get([x as f64, 45.0]);
+        let m = gen.theme.get([x as f64, 46.0]);
+        let (width, height) = match prop {
+            // struts come in pairs of columns, and the deck has a railing on it
+            FactoryProp::Scaffold => (
+                2 * n_to_box1(n, Box1::new(2, 5)),
+                n_to_box1(m, Box1::new(2, DESIGN_JUMP_HEIGHT + 1)) + 1,
+            ),
+            FactoryProp::MetalBoxStack => {
+                (n_to_box1(n, Box1::new(1, 5)), n_to_box1(m, Box1::new(1, 4)))
+            }
+            FactoryProp::Console => (if n_to_bool(n) { 1 } else { 3 }, 1),
+        };
+        Feature::Factory {
+            prop,
+            box2: Box2::from_box1s(Box1::new(x, x + width), Box1::new(ground, ground + height)),
+        }
+    });
+}
+
+// chance that a free spot on flat ground gets a house
+const VILLAGE_DENSITY: f64 = 0.25;
+
+fn village_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>) {
+    flat_props_brush(schema, gen, box2, 55.0, VILLAGE_DENSITY, |x, ground| {
+        let walls = n_to_box1(gen.theme.get([x as f64, 56.0]), Box1::new(3, 7));
+        let storeys = n_to_box1(gen.theme.get([x as f64, 57.0]), Box1::new(2, 4));
+        let rock = match zone {
+            Zone::Desert(_) => RockType::Sandstone,
+            _ => n_to_slice(
+                gen.theme.get([x as f64, 58.0]),
+                &[RockType::Slate, RockType::Stone],
+            ),
+        };
+        Feature::House {
+            box2: Box2::from_box1s(
+                Box1::new(x, x + walls + 2),
+                Box1::new(ground, ground + storeys + 3),
+            ),
+            rock,
+            roof: n_to_enum(gen.theme.get([x as f64, 59.0])),
+            // kept off the corners of the walls
+            door: n_to_box1(gen.theme.get([x as f64, 60.0]), Box1::new(2, walls)) as u32,
+        }
+    });
+}
+
+fn clutter_density(zone: Zone) -> f64 {
+    match zone {
+        Zone::Mushroom => 0.5,
+        Zone::Forest | Zone::SnowForest => 0.3,
+        Zone::Grass(_) => 0.25,
+        Zone::Caverns => 0.2,
+        Zone::StoneMountain | Zone::StoneCliff => 0.15,
+        Zone::Desert(_) | Zone::LavaPlains | Zone::LavaHills => 0.1,
+        Zone::Candy(_) | Zone::Factory(_) | Zone::Castle => 0.0,
+    }
+}
+
+fn clutter_tiles(zone: Zone, terrain: Terrain) -> &'static [Tile] {
+    match (zone, terrain) {
+        (Zone::Mushroom, Terrain::Grass | Terrain::Dirt) => &[
+            Tile::MushroomWhite(false),
+            Tile::MushroomWhite(true),
+            Tile::MushroomRed(false),
+            Tile::MushroomRed(true),
+            Tile::MushroomBrown(false),
+            Tile::MushroomBrown(true),
+            Tile::Moss,
+        ],
+        (_, Terrain::Grass) => &[
+            Tile::GrassTuft,
+            Tile::GrassTuft,
+            Tile::Sapling,
+            Tile::Rock,
+            Tile::MossRock(false),
+            Tile::MossRock(true),
+        ],
+        (_, Terrain::Dirt) => &[Tile::Moss, Tile::Rock, Tile::MushroomBrown(false)],
+        (_, Terrain::Sand) => &[Tile::Cactus, Tile::Cactus, Tile::Rock],
+        (_, Terrain::Snow | Terrain::Tundra) => &[
+            Tile::IceTuft,
+            Tile::FrozenShrub,
+            Tile::IceRock(false),
+            Tile::IceRock(true),
+        ],
+        (_, Terrain::Stone) => &[
+            Tile::Rock,
+            Tile::Moss,
+            Tile::MossRock(false),
+            Tile::MossRock(true),
+        ],
+        (
+            _,
+            Terrain::Cake | Terrain::Choco | Terrain::Metal | Terrain::Castle | Terrain::Industrial,
+        ) => &[],
+    }
+}
+
+fn clutter_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>) {
+    let density = clutter_density(zone);
+
+    // (x, the surface row, the row the clutter goes in)
+    let mut spots = Vec::new();
+    let mut slopes = Vec::new();
+    for f in schema.intersecting(box2) {
+        match f {
+            Feature::FlatGround(p, width) => {
+                spots.extend((p.x..p.x + width as i32).map(|x| (x, p.y, p.y + 1)))
+            }
+            // on a slope the clutter shares the slope tile, so it stands in the ground instead of
+            // hanging over the low corner
+            Feature::SlopedGround { start, height } => {
+                slopes.push(Box1::new(start.x, start.x + height.abs()));
+                spots.extend((0..height.abs()).map(|i| {
+                    let y = start.y + i * height.signum();
+                    (start.x + i, y, y)
+                }));
+            }
+            _ => (),
+        }
+    }
+
+    let mut clutter = Vec::new();
+    for (x, surface, y) in spots {
+        if !box2.contains((x, y))
+            || gen.theme.get([x as f64, 16.0]) >= density
+            // ground under a hill slope is still reported as flat
+            || (y > surface && slopes.iter().any(|s| s.contains(x)))
+        {
+            continue;
+        }
+
+        // only clutter real ground, so nothing ends up on mushroom caps, branches or over a gap
+        let terrain = schema
+            .intersecting(Box2::from_point((x, surface)))
+            .find_map(|f| match f {
+                Feature::GroundBlock(_, terrain, _) | Feature::HillBlock { terrain, .. } => {
+                    Some(terrain)
+                }
+                _ => None,
+            });
+        let occupied = schema
+            .intersecting(Box2::from_point((x, surface + 1)))
+            .any(|f| {
+                !matches!(
+                    f,
+                    Feature::Zone(..)
+                        | Feature::FlatGround(..)
+                        | Feature::SlopedGround { .. }
+                        | Feature::HillBlock { .. }
+                        | Feature::CaveTunnel { .. }
+                )
+            });
+        if let Some(terrain) = terrain && !occupied {
+            let tiles = clutter_tiles(zone, terrain);
+            if !tiles.is_empty() {
+                clutter.push(Feature::Clutter(
+                    Place::new(x, y),
+                    n_to_slice(gen.theme.get([x as f64, 17.0]), tiles),
+                ));
+            }
+        }
+    }
+
+    for f in clutter {
+        schema.add(f);
+    }
+}
+
+const BLOCK_ROW_CHANCE: f64 = 0.3;
+const GAP_ARC_CHANCE: f64 = 0.5;
+const HILL_ARC_CHANCE: f64 = 0.3;
+
+fn bonus_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    block_row_brush(schema, gen, box2);
+    coin_arc_brush(schema, gen, box2);
+}
+
+fn block_row_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    for (p, flat) in flat_spots(schema, box2) {
+        let y = p.y + DESIGN_JUMP_HEIGHT;
+        if gen.theme.get([p.x as f64, 18.0]) >= BLOCK_ROW_CHANCE || y >= box2.y.hi_excl {
+            continue;
+        }
+        let size = n_to_box1(gen.theme.get([p.x as f64, 19.0]), Box1::new(3, 7));
+        if let Some(row) = n_to_fitted_box1(gen.theme.get([p.x as f64, 20.0]), size, flat) {
+            // the row and everything under it has to be free, or it can't be reached
+            if !iproduct!(row.iter(), p.y + 1..=y).all(|(x, y)| is_clear(schema, Place::new(x, y)))
+            {
+                continue;
+            }
+
+            for x in row.iter() {
+                let n = gen.theme.get([x as f64, 21.0]);
+                let bonus = if n < 0.15 {
+                    Bonus::CoinBox
+                } else if n < 0.25 {
+                    Bonus::BangBox
+                } else {
+                    Bonus::Brick
+                };
+                schema.add(Feature::BonusBlock(Place::new(x, y), bonus));
+            }
+        }
+    }
+}
+
+// a hump of the given height over n columns
+fn arc(i: i32, n: i32, height: i32) -> i32 {
+    if n < 2 {
+        return 0;
+    }
+    let t = i as f64 / (n - 1) as f64;
+    (4.0 * height as f64 * t * (1.0 - t)).round() as i32
+}
+
+fn coin_of(n: f64) -> Coin {
+    n_to_slice(
+        n,
+        &[
+            Coin::Bronze,
+            Coin::Bronze,
+            Coin::Bronze,
+            Coin::Silver,
+            Coin::Silver,
+            Coin::Gold,
+        ],
+    )
+}
+
+fn coin_arc_brush(schema: &mut Schema, gen: &Gen, box2: Box2<i32>) {
+    let mut arcs = Vec::new();
+
+    // gaps with ground on both sides, jumped from one lip to the other
+    let gaps = box2
+        .x
+        .iter()
+        .map(|x| (1, x, ground_top(schema, x, box2) == box2.y.lo_incl))
+        .coalesce(|(l1, x1, a), (l2, x2, b)| {
+            if a == b {
+                Ok((l1 + l2, x1, a))
+            } else {
+                Err(((l1, x1, a), (l2, x2, b)))
+            }
+        })
+        .filter_map(|(l, x, gap)| gap.then_some(Box1::new(x, x + l)));
+    for gap in gaps {
+        if gap.lo_incl == box2.x.lo_incl
+            || gap.hi_excl == box2.x.hi_excl
+            || gen.theme.get([gap.lo_incl as f64, 22.0]) >= GAP_ARC_CHANCE
+        {
+            continue;
+        }
+        let lips = Box1::new(gap.lo_incl - 1, gap.hi_excl + 1);
+        let base =
+            ground_top(schema, lips.lo_incl, box2).max(ground_top(schema, lips.hi_excl - 1, box2));
+        let height = (lips.size() / 2).min(DESIGN_JUMP_HEIGHT - 1);
+        arcs.push(
+            lips.iter()
+                .map(|x| Place::new(x, base + arc(x - lips.lo_incl, lips.size(), height)))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    // hills get an arc following the slope up
+    for f in schema.intersecting(box2) {
+        if let Feature::HillBlock {
+            start_x,
+            height,
+            lr,
+            ..
+        } = f
+            && gen.theme.get([start_x as f64, 23.0]) < HILL_ARC_CHANCE
+        {
+            let n = height.size();
+            arcs.push(
+                (start_x..start_x + n)
+                    .map(|x| {
+                        let y = hill_top(start_x, height, lr, x) + 1;
+                        Place::new(x, y + arc(x - start_x, n, 2))
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    for coins in arcs {
+        if !coins
+            .iter()
+            .all(|&p| box2.contains(p.into()) && is_clear(schema, p))
+        {
+            continue;
+        }
+        let coin = coin_of(gen.theme.get([coins[0].x as f64, 24.0]));
+        for p in coins {
+            schema.add(Feature::Coin(p, coin));
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+struct LayeredTile {
+    background: TileStack,
+    midground: TileStack,
+    foreground: TileStack,
+}
+
+fn get_tile(schema: &LevelSchema, gen: &Gen, p: Place) -> LayeredTile {
+    let mut t = LayeredTile::default();
+
     let altn = gen.theme.get([p.x as f64, p.y as f64]);
 
     for f in schema.at_point(p) {
         match f {
-            Feature::GroundBlock(gc, terrain, _) => t.midground = TilingTile::Ground(gc, terrain),
+            Feature::GroundBlock(gc, terrain, _) => {
+                t.midground.push(Z::Base, TilingTile::Ground(gc, terrain))
+            }
             Feature::HillBlock {
                 terrain,
                 start_x,
@@ -364,95 +1408,651 @@ fn get_tile(schema: &Schema, gen: &Gen, p: Place) -> LayeredTile {
                 bridge_thickness,
                 lr,
             } => {
-                let top = match lr {
-                    LR::L => height.lo_incl + (p.x - start_x),
-                    LR::R => height.hi_excl - 1 - (p.x - start_x),
-                };
+                let top = hill_top(start_x, height, lr, p.x);
                 match bridge_thickness {
                     None => {
                         if p.y == top {
-                            t.midground =
-                                TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::Slope(lr)));
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::Slope(lr))),
+                            );
                         } else if p.y < top {
-                            t.midground = TilingTile::Ground(GroundCover::TopCovered, terrain);
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Ground(GroundCover::TopCovered, terrain),
+                            );
                         }
                     }
                     Some(bridge_thickness) => {
                         let bottom = top - bridge_thickness as i32;
                         if p.y == top {
-                            t.midground =
-                                TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::Slope(lr)));
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::Slope(lr))),
+                            );
                         } else if bottom < p.y && p.y < top {
-                            t.midground = TilingTile::Ground(GroundCover::TopCovered, terrain)
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Ground(GroundCover::TopCovered, terrain),
+                            )
                         } else if p.y == bottom {
-                            t.midground = TilingTile::Exactly(Tile::Terrain(
-                                terrain,
-                                TerrainTile::RockSlope(lr.flip(), TB::B),
-                            ));
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Exactly(Tile::Terrain(
+                                    terrain,
+                                    TerrainTile::RockSlope(lr.flip(), TB::B),
+                                )),
+                            );
                         }
                     }
                 }
             }
+            Feature::House {
+                box2: b,
+                rock,
+                roof,
+                door,
+            } => {
+                let walls = Box2::new(
+                    (b.x.lo_incl + 1, b.y.lo_incl),
+                    (b.x.hi_excl - 1, b.y.hi_excl - 3),
+                );
+                let door = b.x.lo_incl + door as i32;
+                let n = |k: f64| gen.theme.get([b.x.lo_incl as f64, k]);
+                // shops hang their sign beside the door, on whichever side has wall
+                let sign = if door + 1 < walls.x.hi_excl - 1 {
+                    door + 1
+                } else {
+                    door - 1
+                };
+                let alt3 = |n: f64| n_to_slice(n, &[Alt3::Alt0, Alt3::Alt1, Alt3::Alt2]);
+
+                if p.y == b.y.hi_excl - 1 {
+                    if p.x == n_to_box1(n(62.0), walls.x) {
+                        t.background
+                            .push(Z::Base, TilingTile::Exactly(Tile::Chimney(alt3(altn))));
+                    }
+                } else if p.y >= walls.y.hi_excl {
+                    let tb = if p.y == walls.y.hi_excl { TB::B } else { TB::T };
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Roof(roof, lmr_of(b.x, p.x), tb)),
+                    );
+                } else if walls.x.contains(p.x) {
+                    let lmr = lmr_of(walls.x, p.x);
+                    let tmb = tmb_of(walls.y, p.y);
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(if lmr == LMR::M && tmb == TMB::M && altn < 0.25 {
+                            Tile::BuildingInt(rock, altn < 0.125)
+                        } else {
+                            Tile::Building(rock, lmr, tmb)
+                        }),
+                    );
+
+                    // doors, windows and signs are drawn over the wall, none of them are solid
+                    let storey = p.y - walls.y.lo_incl;
+                    if p.x == door && storey < 2 {
+                        let tb = if storey == 0 { TB::B } else { TB::T };
+                        t.background.push(
+                            Z::Over,
+                            TilingTile::Exactly(match n_to_index(n(61.0), 6) {
+                                0 => Tile::DoorwayGrey(tb),
+                                1 => Tile::DoorwayBeige(tb),
+                                2 => Tile::DoorLockedGrey(tb),
+                                3 => Tile::DoorLockedBeige(tb),
+                                4 => Tile::DoorInsetBeige(tb),
+                                _ if tb == TB::T => Tile::DoorTopWindow,
+                                _ => Tile::DoorBeige,
+                            }),
+                        );
+                    } else if p.x == sign && storey == 1 && n(63.0) < 0.5 {
+                        t.background.push(
+                            Z::Over,
+                            TilingTile::Exactly(match n_to_index(n(64.0), 3) {
+                                0 => Tile::SignBed { hanging: true },
+                                1 => Tile::SignCoin { hanging: true },
+                                _ => Tile::SignMug { hanging: true },
+                            }),
+                        );
+                    } else if storey > 0 && lmr == LMR::M && (p.x - walls.x.lo_incl) % 2 == 1 {
+                        t.background.push(
+                            Z::Over,
+                            TilingTile::Exactly(match n_to_index(n(65.0), 4) {
+                                0 => Tile::Window(alt3(n(66.0))),
+                                1 => Tile::WindowOpen,
+                                2 => Tile::WindowOpenHalf,
+                                _ => Tile::WindowStainedHalf,
+                            }),
+                        );
+                    }
+                }
+            }
             Feature::Igloo { box2, door } => {
                 let lmr = lmr_of(box2.x, p.x);
                 let tmb = tmb_of(box2.y, p.y);
                 if tmb == TMB::B && box2.x.lo_incl + door as i32 == p.x {
-                    t.background = TilingTile::Exactly(Tile::IglooDoor);
+                    t.background
+                        .push(Z::Base, TilingTile::Exactly(Tile::IglooDoor));
                 } else if tmb == TMB::T {
-                    t.background = TilingTile::Exactly(Tile::IglooTop(lmr));
+                    t.background
+                        .push(Z::Base, TilingTile::Exactly(Tile::IglooTop(lmr)));
                 } else {
-                    t.background = TilingTile::Exactly(Tile::IglooInterior(n_to_bool(altn)));
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::IglooInterior(n_to_bool(altn))),
+                    );
                 }
             }
-            Feature::Tile(_, tile) => t.foreground = TilingTile::Exactly(tile),
-            Feature::CrateCrossRect(_) => t.midground = TilingTile::Exactly(Tile::CrateCross),
-            Feature::CrateRandomRect(_) => {
-                t.midground = TilingTile::Exactly(
-                    [Tile::CrateBlank, Tile::CrateSlash, Tile::CrateCross][n_to_range(altn, 3)],
-                )
+            // terrain tiles join up with the ground around them
+            Feature::Tile(_, tile @ Tile::Terrain(_, _)) => {
+                t.midground.push(Z::Base, TilingTile::Exactly(tile))
             }
-            Feature::SurfaceWater(b) => {
+            Feature::Tile(_, tile) => t.foreground.push(Z::Base, TilingTile::Exactly(tile)),
+            // grass and rocks stay behind anything else that's put on the same spot
+            Feature::Clutter(_, tile) => t.foreground.push(Z::Behind, TilingTile::Exactly(tile)),
+            Feature::CrateCrossRect(_) => t
+                .midground
+                .push(Z::Base, TilingTile::Exactly(Tile::CrateCross)),
+            Feature::CrateRandomRect(_) => t.midground.push(
+                Z::Base,
+                TilingTile::Exactly(
+                    [Tile::CrateBlank, Tile::CrateSlash, Tile::CrateCross][n_to_range(altn, 3)],
+                ),
+            ),
+            Feature::BonusBlock(_, bonus) => t.midground.push(
+                Z::Base,
+                TilingTile::Exactly(match bonus {
+                    Bonus::Brick => Tile::BrickBlock,
+                    Bonus::CoinBox => Tile::CoinBox {
+                        empty: false,
+                        alt: false,
+                    },
+                    Bonus::BangBox => Tile::BangBox {
+                        empty: false,
+                        alt: false,
+                    },
+                }),
+            ),
+            Feature::Coin(_, coin) => t.foreground.push(
+                Z::Base,
+                TilingTile::Exactly(match coin {
+                    Coin::Bronze => Tile::CoinBronze,
+                    Coin::Silver => Tile::CoinSilver,
+                    Coin::Gold => Tile::CoinGold,
+                }),
+            ),
+            // drawn from the liquid layer, which it's poured into
+            Feature::SurfaceLiquid(..) => (),
+            Feature::CastleKeep(b) => {
+                let a = n_to_bool(gen.theme.get([b.x.lo_incl as f64, 36.0]));
+                let lmr = lmr_of(b.x, p.x);
+                let body = Box1::new(b.y.lo_incl, b.y.hi_excl - 1);
                 if p.y == b.y.hi_excl - 1 {
-                    t.background = TilingTile::Exactly(Tile::WaterWave);
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(match lmr {
+                            LMR::L => Tile::CastleRoofSlope(a, LR::L),
+                            LMR::M => Tile::CastleRoof(a, false),
+                            LMR::R => Tile::CastleRoofSlope(a, LR::R),
+                        }),
+                    );
+                } else {
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Castle(a, lmr, tmb_of(body, p.y))),
+                    );
+                    // a tapestry hangs on the back wall from its rod, clear of the floor
+                    let mid = b.x.lo_incl + b.x.size() / 2;
+                    let hang = Box1::new(body.lo_incl + 1, body.hi_excl);
+                    let red = n_to_bool(gen.theme.get([b.x.lo_incl as f64, 73.0]));
+                    let tapestry = if !hang.contains(p.y) || (p.x - mid).abs() > 1 {
+                        None
+                    } else if p.y == hang.hi_excl - 1 {
+                        Some(match (p.x - mid, red) {
+                            (-1, _) => Tile::TapestryHolder(LR::L),
+                            (1, _) => Tile::TapestryHolder(LR::R),
+                            (_, true) => Tile::TapestryTopRed,
+                            (_, false) => Tile::TapestryTopGreen,
+                        })
+                    } else if p.x != mid {
+                        None
+                    } else if p.y == hang.lo_incl {
+                        Some(if red {
+                            Tile::TapestryBottomRed(n_to_bool(altn))
+                        } else {
+                            Tile::TapestryBottomGreen(n_to_bool(altn))
+                        })
+                    } else {
+                        let alt = [Alt5::Alt0, Alt5::Alt1, Alt5::Alt2, Alt5::Alt3, Alt5::Alt4]
+                            [(p.y - hang.lo_incl) as usize % 5];
+                        Some(if red {
+                            Tile::TapestryMidRed(alt)
+                        } else {
+                            Tile::TapestryMidGreen(alt)
+                        })
+                    };
+                    if let Some(tile) = tapestry {
+                        t.background.push(Z::Over, TilingTile::Exactly(tile));
+                    }
+                    if p.y == b.y.lo_incl && p.x == b.x.lo_incl + 1 {
+                        t.background
+                            .push(Z::Over, TilingTile::Exactly(Tile::TorchWood(LR::L)));
+                    } else if p.y == b.y.lo_incl && p.x == b.x.hi_excl - 2 {
+                        t.background
+                            .push(Z::Over, TilingTile::Exactly(Tile::TorchWood(LR::R)));
+                    }
+                }
+            }
+            Feature::CastleTower(b) => {
+                let top = b.y.hi_excl - 1;
+                let lmr = lmr_of(b.x, p.x);
+                if p.y == top {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(match lmr {
+                            LMR::L => Tile::CrenellationOverhang(LR::L, false),
+                            LMR::M => Tile::Crenellation(false),
+                            LMR::R => Tile::CrenellationOverhang(LR::R, false),
+                        }),
+                    );
+                } else if p.y < b.y.lo_incl + 2 {
+                    let tb = if p.y == b.y.lo_incl { TB::B } else { TB::T };
+                    t.background
+                        .push(Z::Base, TilingTile::Exactly(Tile::Gate(lmr, tb, true)));
+                } else if lmr == LMR::M && top - p.y <= 3 {
+                    // a banner hangs from the crenellations
+                    let tmb = tmb_of(Box1::new(top - 3, top), p.y);
+                    let red = n_to_bool(gen.theme.get([b.x.lo_incl as f64, 37.0]));
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(if red {
+                            Tile::BannerSmallRed(tmb, false)
+                        } else {
+                            Tile::BannerSmallGreen(tmb, false)
+                        }),
+                    );
+                } else if lmr == LMR::M && (p.y - b.y.lo_incl) % 3 == 0 {
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::CastleWindowOpen(n_to_bool(altn))),
+                    );
                 } else {
-                    t.background = TilingTile::Exactly(Tile::Water);
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::CastleStoneWall(if n_to_bool(altn) {
+                            Alt3::Alt0
+                        } else {
+                            Alt3::Alt1
+                        })),
+                    );
                 }
             }
-            Feature::SurfaceLava(b) => {
-                if p.y == b.y.hi_excl - 1 {
-                    t.background = TilingTile::Exactly(Tile::LavaWave);
+            Feature::CastleWall { box2, gate } => {
+                let gate = Box1::new(gate, gate + 3);
+                let lmr = lmr_of(box2.x, p.x);
+                if gate.contains(p.x) && p.y < box2.y.lo_incl + 2 {
+                    let tb = if p.y == box2.y.lo_incl { TB::B } else { TB::T };
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Gate(lmr_of(gate, p.x), tb, false)),
+                    );
+                } else if p.y == box2.y.hi_excl - 1 {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::CrenellationsBrickTop(lmr)),
+                    );
+                } else if p.y < box2.y.lo_incl + 2 {
+                    // walked past on the way to the gate
+                    t.background
+                        .push(Z::Base, TilingTile::Exactly(Tile::CrenellationsBrick(lmr)));
                 } else {
-                    t.background = TilingTile::Exactly(Tile::Lava);
+                    t.midground
+                        .push(Z::Base, TilingTile::Exactly(Tile::CrenellationsBrick(lmr)));
+                }
+            }
+            Feature::Candy { prop, box2 } => {
+                let n = gen.theme.get([box2.x.lo_incl as f64, 41.0]);
+                let top = p.y == box2.y.hi_excl - 1;
+                match prop {
+                    CandyProp::Lollipop => t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(if top {
+                            let m = gen.theme.get([box2.x.lo_incl as f64, 42.0]);
+                            [
+                                Tile::LollipopGreen,
+                                Tile::LollipopRed,
+                                Tile::LollipopYellow,
+                                Tile::LollipopGreenSwirl(n_to_bool(m)),
+                                Tile::LollipopRedSwirl(n_to_bool(m)),
+                            ][n_to_index(n, 5)]
+                        } else if p.y == box2.y.lo_incl {
+                            [Tile::LollipopBaseBeige, Tile::LollipopBasePink][n_to_index(n, 2)]
+                        } else {
+                            [
+                                Tile::LollipopStickWhite,
+                                Tile::LollipopStickBeige,
+                                Tile::LollipopStickBrown,
+                            ][n_to_index(n, 3)]
+                        }),
+                    ),
+                    // the hook of the cane is two tiles wide, over the stem on the left
+                    CandyProp::CandyCane => {
+                        let right = p.x != box2.x.lo_incl;
+                        let cane = n_to_index(n, 3);
+                        if top {
+                            t.background.push(
+                                Z::Base,
+                                TilingTile::Exactly(
+                                    [
+                                        Tile::CandyCaneTopRed(right),
+                                        Tile::CandyCaneTopGreen(right),
+                                        Tile::CandyCaneTopPink(right),
+                                    ][cane],
+                                ),
+                            );
+                        } else if !right {
+                            t.background.push(
+                                Z::Base,
+                                TilingTile::Exactly(
+                                    [
+                                        Tile::CandyCaneBaseRed,
+                                        Tile::CandyCaneBaseGreen,
+                                        Tile::CandyCaneBasePink,
+                                    ][cane],
+                                ),
+                            );
+                        }
+                    }
+                    CandyProp::CandyPole => {
+                        let tb = if top { TB::T } else { TB::B };
+                        t.midground.push(
+                            Z::Base,
+                            TilingTile::Exactly(
+                                [
+                                    Tile::CandyPoleBrown(tb),
+                                    Tile::CandyPoleGreen(tb),
+                                    Tile::CandyPolePink(tb),
+                                    Tile::CandyPoleRed(tb),
+                                ][n_to_index(n, 4)],
+                            ),
+                        );
+                    }
+                    CandyProp::IceCream => {
+                        t.background.push(
+                            Z::Base,
+                            TilingTile::Exactly(if top {
+                                [
+                                    Tile::IcecreamBeige,
+                                    Tile::IcecreamWhite,
+                                    Tile::IcecreamPink,
+                                    Tile::IcecreamBrown,
+                                ][n_to_index(n, 4)]
+                            } else {
+                                Tile::Cone
+                            }),
+                        );
+                    }
+                    // a worm humps along from its hook to its tail
+                    CandyProp::GummyWorm => {
+                        let red = n_to_bool(n);
+                        t.foreground.push(
+                            Z::Base,
+                            TilingTile::Exactly(match (lmr_of(box2.x, p.x), red) {
+                                (LMR::L, false) => Tile::GummyWormTailGreenYellow,
+                                (LMR::M, false) => Tile::GummyWormLoopGreenYellow,
+                                (LMR::R, false) => Tile::GummyWormHookGreenYellow,
+                                (LMR::L, true) => Tile::GummyWormTailRedWhite,
+                                (LMR::M, true) => Tile::GummyWormLoopRedWhite,
+                                (LMR::R, true) => Tile::GummyWormHookRedWhite,
+                            }),
+                        );
+                    }
+                }
+            }
+            Feature::Factory { prop, box2 } => {
+                let lmr = lmr_of(box2.x, p.x);
+                match prop {
+                    FactoryProp::Scaffold => {
+                        let deck = box2.y.hi_excl - 2;
+                        // struts and beams go in pairs of columns
+                        let lr = if (p.x - box2.x.lo_incl) % 2 == 0 {
+                            LR::L
+                        } else {
+                            LR::R
+                        };
+                        if p.y > deck {
+                            t.foreground
+                                .push(Z::Base, TilingTile::Exactly(Tile::MetalFence(lmr)));
+                        } else if p.y == deck {
+                            t.midground.push(
+                                Z::Base,
+                                TilingTile::Exactly(Tile::Girder {
+                                    bolts: lmr != LMR::M,
+                                }),
+                            );
+                        } else if (deck - box2.y.lo_incl) % 2 == 1 && p.y == box2.y.lo_incl {
+                            // the row left over under the cross-bracing gets a V of beams
+                            t.background
+                                .push(Z::Base, TilingTile::Exactly(Tile::Beam(lr)));
+                        } else {
+                            // cross-bracing in two by two blocks, hanging from the deck
+                            let tb = if (deck - p.y) % 2 == 1 { TB::T } else { TB::B };
+                            t.background
+                                .push(Z::Base, TilingTile::Exactly(Tile::Strut(lr, tb)));
+                        }
+                    }
+                    FactoryProp::MetalBoxStack => {
+                        let alt = n_to_bool(gen.theme.get([box2.x.lo_incl as f64, 47.0]));
+                        t.midground.push(
+                            Z::Base,
+                            TilingTile::Exactly(
+                                [
+                                    Tile::MetalBoxWire(alt),
+                                    Tile::MetalBoxCross(alt),
+                                    Tile::MetalBoxSlash(alt),
+                                    Tile::MetalBoxBlank(alt),
+                                ][n_to_index(altn, 4)],
+                            ),
+                        );
+                    }
+                    FactoryProp::Console => {
+                        let on = n_to_bool(gen.theme.get([box2.x.lo_incl as f64, 48.0]));
+                        t.foreground.push(
+                            Z::Base,
+                            TilingTile::Exactly(Tile::Console {
+                                lmr: (box2.x.size() > 1).then_some(lmr),
+                                on,
+                                knobs: n_to_bool(altn),
+                            }),
+                        );
+                    }
                 }
             }
             Feature::BigMushroomTop(center, width) => {
                 let style = n_to_enum(gen.theme.get([center.x as f64, center.y as f64]));
                 let alt = n_to_bool(altn);
                 if p.x == center.x {
-                    t.midground = TilingTile::Exactly(Tile::MushroomStemBlock(style, alt));
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::MushroomStemBlock(style, alt)),
+                    );
                 } else if p.x == center.x - width as i32 {
-                    t.midground = TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::L));
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::L)),
+                    );
                 } else if p.x == center.x + width as i32 {
-                    t.midground = TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::R));
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::R)),
+                    );
                 } else {
-                    t.midground = TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::M));
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::MushroomBlock(style, alt, LMR::M)),
+                    );
                 }
             }
             Feature::BigMushroomStem(base, height) => {
                 if p.y == base.y + height as i32 {
-                    t.background = TilingTile::Exactly(Tile::MushroomStemTop(n_to_bool(altn)));
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::MushroomStemTop(n_to_bool(altn))),
+                    );
                 } else {
-                    t.background = TilingTile::Exactly(
-                        [
-                            Tile::MushroomStem,
-                            Tile::MushroomStemLeaf,
-                            Tile::MushroomStemRing(false),
-                            Tile::MushroomStemRing(true),
-                        ][n_to_range(altn, 4)],
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(
+                            [
+                                Tile::MushroomStem,
+                                Tile::MushroomStemLeaf,
+                                Tile::MushroomStemRing(false),
+                                Tile::MushroomStemRing(true),
+                            ][n_to_range(altn, 4)],
+                        ),
                     )
                 }
             }
 
+            Feature::PineTree { base, height, snow } => {
+                let top = base.y + height as i32 - 1;
+                let lr = match p.x - base.x {
+                    -1 => Some(LR::L),
+                    1 => Some(LR::R),
+                    _ => None,
+                };
+                match lr {
+                    None if p.y == base.y => t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(if snow {
+                            Tile::TrunkBaseSnowPile
+                        } else {
+                            Tile::TrunkBaseNarrow
+                        }),
+                    ),
+                    None if p.y == top => t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::PineTree(TreeTile::Top { snow })),
+                    ),
+                    None => t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::PineTree(TreeTile::PineTrunk)),
+                    ),
+                    Some(lr) if p.y != base.y && p.y != top => t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::PineTree(TreeTile::PineBranch {
+                            snow,
+                            lr,
+                            double: (p.y - base.y) % 2 == 0,
+                        })),
+                    ),
+                    Some(_) => (),
+                }
+            }
+            Feature::BroadleafTree { base, height, snow } => {
+                let top = base.y + height as i32 - 1;
+                let branch = top - 1;
+                if p.x == base.x {
+                    t.background.push(
+                        Z::Base,
+                        TilingTile::Exactly(if p.y == base.y {
+                            if snow {
+                                Tile::TrunkBaseSnow
+                            } else {
+                                Tile::TrunkBase
+                            }
+                        } else if p.y == top {
+                            let lr = if n_to_bool(gen.theme.get([base.x as f64, base.y as f64])) {
+                                LR::L
+                            } else {
+                                LR::R
+                            };
+                            Tile::TrunkFork(lr)
+                        } else {
+                            Tile::TrunkStraight
+                        }),
+                    );
+                }
+                if p.y == branch {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::TrunkBranch(lmr_of(
+                            Box1::new(base.x - 1, base.x + 2),
+                            p.x,
+                        ))),
+                    );
+                }
+            }
+
+            Feature::CaveCeiling { box2, cave, spikes } => {
+                if p.y == box2.y.lo_incl {
+                    if gen.theme.get([p.x as f64, 15.0]) < spikes as f64 / 100.0 {
+                        t.midground.push(
+                            Z::Base,
+                            TilingTile::Exactly(Tile::Cave(cave, CaveTile::Spike(TB::T))),
+                        );
+                    }
+                } else if p.y == box2.y.lo_incl + 1 {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Cave(cave, CaveTile::Jagged(TB::T))),
+                    );
+                } else {
+                    t.midground
+                        .push(Z::Base, TilingTile::Ground(GroundCover::Bare, cave.into()));
+                }
+            }
+            Feature::SkyIsland { terrain, box2 } => {
+                let top = box2.y.hi_excl - 1;
+                let lr = if p.x == box2.x.lo_incl { LR::L } else { LR::R };
+                let end = p.x == box2.x.lo_incl || p.x == box2.x.hi_excl - 1;
+                if box2.y.size() == 1 && box2.x.size() == 2 {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::RoundLedge(lr))),
+                    );
+                } else if p.y == top && end {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Terrain(terrain, TerrainTile::OverLedge(lr))),
+                    );
+                } else if p.y == top {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Exactly(Tile::Terrain(
+                            terrain,
+                            TerrainTile::BlockFace(LMR::M, TMB::T),
+                        )),
+                    );
+                } else if !end {
+                    t.midground.push(
+                        Z::Base,
+                        TilingTile::Ground(GroundCover::FullyCovered, terrain),
+                    );
+                }
+            }
+            Feature::CaveTunnel { box2, cave } => {
+                t.background.push(
+                    Z::Base,
+                    TilingTile::Exactly(Tile::Terrain(cave.into(), TerrainTile::BareBlock)),
+                );
+                // the corner pieces lean into the tunnel walls
+                if p.y == box2.y.hi_excl - 1 {
+                    if p.x == box2.x.lo_incl {
+                        t.midground.push(
+                            Z::Base,
+                            TilingTile::Exactly(Tile::Cave(cave, CaveTile::Slope(LR::R, TB::B))),
+                        );
+                    } else if p.x == box2.x.hi_excl - 1 {
+                        t.midground.push(
+                            Z::Base,
+                            TilingTile::Exactly(Tile::Cave(cave, CaveTile::Slope(LR::L, TB::B))),
+                        );
+                    }
+                }
+            }
+
             Feature::SlopedGround { .. }
             | Feature::FlatGround(_, _)
             | Feature::Zone(_, _)
@@ -460,19 +2060,40 @@ fn get_tile(schema: &Schema, gen: &Gen, p: Place) -> LayeredTile {
         }
     }
 
+    // liquid shows through behind trunks, stems and walls that stand in it
+    if let Some(liquid) = schema.liquid().tile_at(p, n_to_bool(altn)) {
+        t.background.push(Z::Behind, TilingTile::Exactly(liquid));
+    }
+
     t
 }
 
-pub fn render_level(schema: &Schema, gen: &Gen, box2: Box2<i32>) -> ndarray::Array3<Tile> {
+// whether liquid is kept out of p
+pub fn is_solid(schema: &LevelSchema, gen: &Gen, p: Place) -> bool {
+    !matches!(
+        get_tile(schema, gen, p).midground.top(),
+        TilingTile::Exactly(Tile::Air)
+    )
+}
+
+// Draws the tiles in `box2` as a stack of levels: as many for the background as its tallest
+// stack needs, then the midground, the foreground and lastly the caps, which hang over whatever
+// is beside the ground they belong to
+pub fn render_level(
+    schema: &LevelSchema,
+    gen: &Gen,
+    rules: &TilingRules,
+    box2: Box2<i32>,
+) -> ndarray::Array3<Tile> {
     let extended = Box2 {
         x: Box1::new(box2.x.lo_incl - 1, box2.x.hi_excl + 1),
         y: Box1::new(box2.y.lo_incl - 1, box2.y.hi_excl + 1),
     };
     let shape = [box2.x.size() as usize, box2.y.size() as usize];
     let shape_ex = [extended.x.size() as usize, extended.y.size() as usize];
-    let mut back = ndarray::Array::from_elem(shape_ex, TilingTile::Exactly(Tile::Air));
-    let mut mid = ndarray::Array::from_elem(shape_ex, TilingTile::Exactly(Tile::Air));
-    let mut fore = ndarray::Array::from_elem(shape_ex, TilingTile::Exactly(Tile::Air));
+    let mut back = ndarray::Array::from_elem(shape_ex, TileStack::default());
+    let mut mid = ndarray::Array::from_elem(shape_ex, TileStack::default());
+    let mut fore = ndarray::Array::from_elem(shape_ex, TileStack::default());
 
     for (i, j) in iproduct!(extended.x.iter(), extended.y.iter()) {
         let i_ = (i - extended.x.lo_incl) as usize;
@@ -488,26 +2109,130 @@ pub fn render_level(schema: &Schema, gen: &Gen, box2: Box2<i32>) -> ndarray::Arr
         fore[[i_, j_]] = foreground;
     }
 
-    let mid2 = compute_tiling(mid);
+    // only one tile of each midground stack joins up with the ground around it
+    let mid2 = compute_tiling(mid.map(TileStack::tiled), rules);
 
-    let mut array = ndarray::Array::from_elem([shape[0], shape[1], 5], Tile::Air);
+    let cells: Vec<_> = iproduct!(0..shape[0], 0..shape[1])
+        .map(|(i_, j_)| {
+            let (tile, caps) = mid2[[i_, j_]];
+            let midground: Vec<_> = mid[[i_ + 1, j_ + 1]].tiles_with(tile).collect();
+            let back: Vec<_> = back[[i_ + 1, j_ + 1]].tiles().collect();
+            let fore: Vec<_> = fore[[i_ + 1, j_ + 1]].tiles().collect();
+            ((i_, j_), [back, midground, fore], caps)
+        })
+        .collect();
 
-    for (i, j) in iproduct!(box2.x.iter(), box2.y.iter()) {
-        let i_ = (i - box2.x.lo_incl) as usize;
-        let j_ = (j - box2.y.lo_incl) as usize;
-        if let TilingTile::Exactly(t) = back[[i_ + 1, j_ + 1]] {
-            array[[i_, j_, 0]] = t;
+    let mut depths = [1; 3];
+    for (_, layers, _) in &cells {
+        for (d, l) in depths.iter_mut().zip(layers) {
+            *d = (*d).max(l.len());
         }
-        array[[i_, j_, 1]] = mid2[[i_, j_]].0;
-        if let TilingTile::Exactly(t) = fore[[i_ + 1, j_ + 1]] {
-            array[[i_, j_, 2]] = t;
+    }
+    let caps_at: usize = depths.iter().sum();
+
+    let mut array = ndarray::Array::from_elem([shape[0], shape[1], caps_at + 2], Tile::Air);
+    for ((i_, j_), layers, caps) in cells {
+        let mut k = 0;
+        for (layer, depth) in layers.into_iter().zip(depths) {
+            for (n, t) in layer.into_iter().enumerate() {
+                array[[i_, j_, k + n]] = t;
+            }
+            k += depth;
         }
-        if let Some(terrain) = mid2[[i_, j_]].1.left_cap {
-            array[[i_, j_, 3]] = Tile::Terrain(terrain, TerrainTile::Cap(LR::L));
+        if let Some(terrain) = caps.left_cap {
+            array[[i_, j_, caps_at]] = Tile::Terrain(terrain, TerrainTile::Cap(LR::L));
         }
-        if let Some(terrain) = mid2[[i_, j_]].1.right_cap {
-            array[[i_, j_, 4]] = Tile::Terrain(terrain, TerrainTile::Cap(LR::R));
+        if let Some(terrain) = caps.right_cap {
+            array[[i_, j_, caps_at + 1]] = Tile::Terrain(terrain, TerrainTile::Cap(LR::R));
         }
     }
     array
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each band is only repaired on its own, so this catches the zones not lining up
+    #[test]
+    fn surface_can_be_crossed_from_spawn_to_right_edge() {
+        for seed in [1, 2, 3] {
+            let gen = Gen::from_seed(seed);
+            let mut level = LevelSchema::default();
+            let b = Box2::new((0, -ZONE_HEIGHT), (1000, ZONE_HEIGHT + 1));
+            generate_level(&gen, &mut level, b);
+            let nav = NavGraph::new(level.intersecting(b));
+            let (_, right) = nav.ends().unwrap();
+            assert_eq!(
+                nav.check(Place::new(0, ZONE_HEIGHT), right),
+                Ok(()),
+                "seed {seed}"
+            );
+        }
+    }
+
+    // the features in `b`, in an order that doesn't depend on how they were put in, and the tiles
+    // they draw
+    fn contents(
+        gen: &Gen,
+        level: &LevelSchema,
+        b: Box2<i32>,
+    ) -> (Vec<String>, ndarray::Array3<Tile>) {
+        let features = level
+            .intersecting(b)
+            .map(|f| format!("{f:?}"))
+            .sorted()
+            .collect();
+        (
+            features,
+            render_level(level, gen, &TilingRules::default(), b),
+        )
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_level() {
+        let b = Box2::new(
+            (-200, -BAND_HEIGHT - ZONE_HEIGHT),
+            (400, BAND_HEIGHT + ZONE_HEIGHT),
+        );
+        for seed in [1, 2, 3] {
+            let levels: Vec<_> = (0..2)
+                .map(|_| {
+                    let gen = Gen::from_seed(seed);
+                    let mut level = LevelSchema::default();
+                    generate_level(&gen, &mut level, b);
+                    contents(&gen, &level, b)
+                })
+                .collect();
+            assert!(levels[0] == levels[1], "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn generation_order_does_not_matter() {
+        let b = Box2::new(
+            (-192, -BAND_HEIGHT - ZONE_HEIGHT),
+            (384, BAND_HEIGHT + ZONE_HEIGHT),
+        );
+        for seed in [1, 2, 3] {
+            let gen = Gen::from_seed(seed);
+            let mut whole = LevelSchema::default();
+            generate_level(&gen, &mut whole, b);
+
+            // chunk by chunk the way the camera would come across them, starting from the far
+            // right and working back left, top to bottom
+            let mut pieces = LevelSchema::default();
+            let xs = (b.x.lo_incl..b.x.hi_excl).step_by(32).rev();
+            let chunks = iproduct!(xs, (b.y.lo_incl..b.y.hi_excl).step_by(32).rev());
+            for (x, y) in chunks {
+                let chunk = Box2::new((x, y), (x + 32, y + 32)).intersect(b);
+                generate_level(&gen, &mut pieces, chunk);
+            }
+
+            assert!(
+                contents(&gen, &whole, b) == contents(&gen, &pieces, b),
+                "seed {seed}"
+            );
+        }
+    }
+}
@@ -1,12 +1,20 @@
+use bevy::prelude::Resource;
 use enum_iterator::Sequence;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use rstar::iterators::LocateAllAtPoint;
 use rstar::iterators::LocateInEnvelopeIntersecting;
 use rstar::*;
+use std::collections::BTreeMap;
 use std::iter::Copied;
 
-use super::{tile::*, Place};
+use super::{
+    brushes::Gen,
+    liquid::LiquidLayer,
+    pacing::{section, Acts, Section},
+    tile::*,
+    Place,
+};
 use crate::helpers::*;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Sequence, FromPrimitive)]
@@ -22,6 +30,7 @@ pub enum Zone {
     Grass(Zone1),
     Desert(Zone1),
     Candy(Zone1),
+    Factory(Zone1),
 
     Mushroom,
     Caverns,
@@ -34,17 +43,28 @@ pub enum Zone {
     Castle,
 }
 
+// how a zone's ground gets laid out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Brushes,
+    // solved from the tiles' adjacency rules, see `wfc`
+    Wfc,
+}
+
 pub struct ZoneInfo {
     pub gap_chance: f64,
     pub hill_chance: f64,
     pub terrain: Terrain,
     pub alt_terrain: Option<Terrain>,
+    pub backend: Backend,
+    // how far up the difficulty curve the level has to be before the zone turns up
+    pub min_difficulty: f64,
 }
 
 impl Zone {
     pub fn info(self) -> ZoneInfo {
         let (gap_chance, hill_chance) = match self {
-            Zone::Grass(z1) | Zone::Desert(z1) | Zone::Candy(z1) => match z1 {
+            Zone::Grass(z1) | Zone::Desert(z1) | Zone::Candy(z1) | Zone::Factory(z1) => match z1 {
                 Zone1::Plains => (0.1, 0.1),
                 Zone1::Hills => (0.2, 0.9),
                 Zone1::Lake => (0.4, 0.3),
@@ -64,6 +84,7 @@ impl Zone {
             Zone::Grass(_) | Zone::Forest => (Terrain::Grass, None),
             Zone::Desert(_) => (Terrain::Sand, None),
             Zone::Candy(_) => (Terrain::Cake, Some(Terrain::Choco)),
+            Zone::Factory(_) => (Terrain::Metal, Some(Terrain::Industrial)),
             Zone::Mushroom => (Terrain::Dirt, Some(Terrain::Grass)),
             Zone::Caverns => (Terrain::Stone, None),
             Zone::SnowForest => (Terrain::Snow, None),
@@ -72,13 +93,43 @@ impl Zone {
             Zone::Castle => (Terrain::Castle, None),
         };
 
+        let backend = match self {
+            Zone::StoneMountain | Zone::StoneCliff => Backend::Wfc,
+            _ => Backend::Brushes,
+        };
+
+        let min_difficulty = match self {
+            Zone::Grass(z1) | Zone::Desert(z1) | Zone::Candy(z1) | Zone::Factory(z1) => match z1 {
+                Zone1::Plains | Zone1::Sky => 0.0,
+                Zone1::Hills => 0.2,
+                Zone1::Lake => 0.3,
+            },
+            Zone::Caverns | Zone::Forest | Zone::SnowForest | Zone::Castle => 0.0,
+            Zone::StoneCliff => 0.3,
+            Zone::StoneMountain => 0.4,
+            Zone::Mushroom | Zone::LavaPlains => 0.5,
+            Zone::LavaHills => 0.6,
+        };
+
         ZoneInfo {
             gap_chance,
             hill_chance,
             terrain,
             alt_terrain,
+            backend,
+            min_difficulty,
         }
     }
+
+    pub fn is_sky(self) -> bool {
+        matches!(
+            self,
+            Zone::Grass(Zone1::Sky)
+                | Zone::Desert(Zone1::Sky)
+                | Zone::Candy(Zone1::Sky)
+                | Zone::Factory(Zone1::Sky)
+        )
+    }
 }
 
 impl FromPrimitive for Zone {
@@ -102,12 +153,82 @@ impl FromPrimitive for Zone {
             n if n < 9 + 1 * Z1 => Some(Zone::Grass(Zone1::from_u64((n - 8) % Z1)?)),
             n if n < 9 + 2 * Z1 => Some(Zone::Desert(Zone1::from_u64((n - 8) % Z1)?)),
             n if n < 9 + 3 * Z1 => Some(Zone::Candy(Zone1::from_u64((n - 8) % Z1)?)),
+            n if n < 9 + 4 * Z1 => Some(Zone::Factory(Zone1::from_u64((n - 8) % Z1)?)),
 
             _ => None,
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bonus {
+    Brick,
+    CoinBox,
+    BangBox,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Coin {
+    Bronze,
+    Silver,
+    Gold,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
+pub enum Liquid {
+    Water,
+    IceWater,
+    SparklingWater,
+    Lava,
+}
+
+impl Liquid {
+    // the top row of a body of liquid
+    pub fn wave(self) -> Tile {
+        match self {
+            Liquid::Water => Tile::WaterWave,
+            Liquid::IceWater => Tile::IceWaterWave,
+            Liquid::SparklingWater => Tile::SparklingWaterWave,
+            Liquid::Lava => Tile::LavaWave,
+        }
+    }
+
+    // the top of a body only one column across
+    pub fn single(self) -> Tile {
+        match self {
+            Liquid::Water => Tile::WaterSingle,
+            Liquid::IceWater => Tile::IceWaterSingle,
+            Liquid::SparklingWater => Tile::SparklingWaterSingle,
+            Liquid::Lava => Tile::LavaSingle,
+        }
+    }
+
+    pub fn body(self) -> Tile {
+        match self {
+            Liquid::Water => Tile::Water,
+            Liquid::IceWater => Tile::IceWater,
+            Liquid::SparklingWater => Tile::SparklingWater,
+            Liquid::Lava => Tile::Lava,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
+pub enum CandyProp {
+    Lollipop,
+    CandyCane,
+    CandyPole,
+    IceCream,
+    GummyWorm,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
+pub enum FactoryProp {
+    Scaffold,
+    MetalBoxStack,
+    Console,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Feature {
     GroundBlock(GroundCover, Terrain, Box2<i32>),
@@ -122,13 +243,64 @@ pub enum Feature {
         box2: Box2<i32>,
         door: u32,
     },
+    // walls under a roof that overhangs them by a column, with a row on top for the chimney
+    House {
+        box2: Box2<i32>,
+        rock: RockType,
+        roof: Roof,
+        door: u32,
+    },
     Tile(Place, Tile),
+    // a decorative foreground tile with no collision
+    Clutter(Place, Tile),
     CrateCrossRect(Box2<i32>),
     CrateRandomRect(Box2<i32>),
-    SurfaceWater(Box2<i32>),
-    SurfaceLava(Box2<i32>),
+    BonusBlock(Place, Bonus),
+    Coin(Place, Coin),
+    SurfaceLiquid(Liquid, Box2<i32>),
+    CastleKeep(Box2<i32>),
+    // towers have a gate through their bottom two rows
+    CastleTower(Box2<i32>),
+    // a curtain wall, with a gate through it starting at the given column
+    CastleWall {
+        box2: Box2<i32>,
+        gate: i32,
+    },
+    Candy {
+        prop: CandyProp,
+        box2: Box2<i32>,
+    },
+    Factory {
+        prop: FactoryProp,
+        box2: Box2<i32>,
+    },
     BigMushroomTop(Place, u32),
     BigMushroomStem(Place, u32),
+    PineTree {
+        base: Place,
+        height: u32,
+        snow: bool,
+    },
+    BroadleafTree {
+        base: Place,
+        height: u32,
+        snow: bool,
+    },
+    // spikes hang from the bottom row of as many columns in a hundred as `spikes` says
+    CaveCeiling {
+        box2: Box2<i32>,
+        cave: Cave,
+        spikes: u32,
+    },
+    CaveTunnel {
+        box2: Box2<i32>,
+        cave: Cave,
+    },
+    // a ledge along the top, narrowing by a column on each side underneath
+    SkyIsland {
+        terrain: Terrain,
+        box2: Box2<i32>,
+    },
 
     SlopedGround {
         start: Place,
@@ -153,13 +325,27 @@ impl Feature {
                 ..
             } => "Hill bridge",
             Feature::Igloo { .. } => "Igloo",
+            Feature::House { .. } => "House",
             Feature::Tile(_, _) => "Tile",
+            Feature::Clutter(_, _) => "Clutter",
             Feature::CrateCrossRect(_) => "Cross crates",
             Feature::CrateRandomRect(_) => "Random crates",
-            Feature::SurfaceWater(_) => "Surface water",
-            Feature::SurfaceLava(_) => "Surface lava",
+            Feature::BonusBlock(_, _) => "Bonus block",
+            Feature::Coin(_, _) => "Coin",
+            Feature::SurfaceLiquid(Liquid::Lava, _) => "Surface lava",
+            Feature::SurfaceLiquid(_, _) => "Surface water",
+            Feature::CastleKeep(_) => "Castle keep",
+            Feature::CastleTower(_) => "Castle tower",
+            Feature::CastleWall { .. } => "Castle wall",
+            Feature::Candy { .. } => "Candy",
+            Feature::Factory { .. } => "Factory",
             Feature::BigMushroomTop(_, _) => "Mushroom top",
             Feature::BigMushroomStem(_, _) => "Mushroom stem",
+            Feature::PineTree { .. } => "Pine tree",
+            Feature::BroadleafTree { .. } => "Broadleaf tree",
+            Feature::CaveCeiling { .. } => "Cave ceiling",
+            Feature::CaveTunnel { .. } => "Cave tunnel",
+            Feature::SkyIsland { .. } => "Sky island",
             Feature::SlopedGround { .. } => "Sloped ground",
             Feature::FlatGround(_, _) => "Flat ground",
             Feature::Zone(_, _) => "Zone",
@@ -183,11 +369,22 @@ impl Feature {
                 (start_x + height.size(), height.hi_excl),
             ),
             Feature::Igloo { box2, .. } => box2,
-            Feature::Tile(p, _) => Box2::from_point(p.into()),
+            Feature::House { box2, .. } => box2,
+            Feature::CaveCeiling { box2, .. } => box2,
+            Feature::CaveTunnel { box2, .. } => box2,
+            Feature::SkyIsland { box2, .. } => box2,
+            Feature::Candy { box2, .. } => box2,
+            Feature::Factory { box2, .. } => box2,
+            Feature::CastleKeep(b) => b,
+            Feature::CastleTower(b) => b,
+            Feature::CastleWall { box2, .. } => box2,
+            Feature::Tile(p, _)
+            | Feature::Clutter(p, _)
+            | Feature::BonusBlock(p, _)
+            | Feature::Coin(p, _) => Box2::from_point(p.into()),
             Feature::CrateCrossRect(b) => b,
             Feature::CrateRandomRect(b) => b,
-            Feature::SurfaceWater(b) => b,
-            Feature::SurfaceLava(b) => b,
+            Feature::SurfaceLiquid(_, b) => b,
             Feature::BigMushroomTop(p, width) => Box2::from_box1s(
                 Box1::new(p.x - width as i32, p.x + width as i32 + 1),
                 Box1::from_point(p.y),
@@ -195,6 +392,11 @@ impl Feature {
             Feature::BigMushroomStem(p, height) => {
                 Box2::from_box1s(Box1::from_point(p.x), Box1::new(p.y, p.y + height as i32))
             }
+            Feature::PineTree { base, height, .. }
+            | Feature::BroadleafTree { base, height, .. } => Box2::from_box1s(
+                Box1::new(base.x - 1, base.x + 2),
+                Box1::new(base.y, base.y + height as i32),
+            ),
             Feature::SlopedGround { start, height } => Box2::new(
                 (start.x, start.y),
                 (start.x + height.abs(), start.y + height.abs()),
@@ -206,6 +408,88 @@ impl Feature {
             Feature::Offscreen(b) => b,
         }
     }
+
+    // the walkable surface that comes with a solid feature
+    pub fn surface(self) -> Option<Feature> {
+        match self {
+            Feature::GroundBlock(_, _, b) => Some(Feature::FlatGround(
+                Place::new(b.x.lo_incl, b.y.hi_excl - 1),
+                b.x.size() as u32,
+            )),
+            Feature::HillBlock {
+                start_x,
+                height,
+                lr,
+                ..
+            } => Some(Feature::SlopedGround {
+                start: Place::new(
+                    start_x,
+                    match lr {
+                        LR::L => height.lo_incl,
+                        LR::R => height.hi_excl - 1,
+                    },
+                ),
+                height: height.size() * -i32::from(lr),
+            }),
+            Feature::BigMushroomTop(p, width) => Some(Feature::FlatGround(
+                p - Place::new(width as i32, 0),
+                2 * width + 1,
+            )),
+            Feature::CastleKeep(b)
+            | Feature::CastleTower(b)
+            | Feature::CastleWall { box2: b, .. }
+            | Feature::SkyIsland { box2: b, .. } => Some(Feature::FlatGround(
+                Place::new(b.x.lo_incl, b.y.hi_excl - 1),
+                b.x.size() as u32,
+            )),
+            Feature::Candy {
+                prop: CandyProp::Lollipop | CandyProp::CandyPole,
+                box2: b,
+            } => Some(Feature::FlatGround(
+                Place::new(b.x.lo_incl, b.y.hi_excl - 1),
+                b.x.size() as u32,
+            )),
+            // a scaffold's deck is under its railing
+            Feature::Factory {
+                prop: FactoryProp::Scaffold,
+                box2: b,
+            } => Some(Feature::FlatGround(
+                Place::new(b.x.lo_incl, b.y.hi_excl - 2),
+                b.x.size() as u32,
+            )),
+            Feature::Factory {
+                prop: FactoryProp::MetalBoxStack,
+                box2: b,
+            } => Some(Feature::FlatGround(
+                Place::new(b.x.lo_incl, b.y.hi_excl - 1),
+                b.x.size() as u32,
+            )),
+            // the branch sits just under the top of the trunk
+            Feature::BroadleafTree { base, height, .. } => Some(Feature::FlatGround(
+                Place::new(base.x - 1, base.y + height as i32 - 2),
+                3,
+            )),
+            Feature::Igloo { .. }
+            | Feature::House { .. }
+            | Feature::Tile(_, _)
+            | Feature::Clutter(_, _)
+            | Feature::CrateCrossRect(_)
+            | Feature::CrateRandomRect(_)
+            | Feature::BonusBlock(_, _)
+            | Feature::Coin(_, _)
+            | Feature::SurfaceLiquid(_, _)
+            | Feature::BigMushroomStem(_, _)
+            | Feature::PineTree { .. }
+            | Feature::CaveCeiling { .. }
+            | Feature::CaveTunnel { .. }
+            | Feature::Candy { .. }
+            | Feature::Factory { .. }
+            | Feature::SlopedGround { .. }
+            | Feature::FlatGround(_, _)
+            | Feature::Zone(_, _)
+            | Feature::Offscreen(_) => None,
+        }
+    }
 }
 
 impl RTreeObject for Feature {
@@ -229,62 +513,49 @@ impl PointDistance for Feature {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Sky,
+    Surface,
+    Underground,
+}
+
+impl Layer {
+    pub fn of_band(band: i32) -> Self {
+        match band {
+            b if b > 0 => Layer::Sky,
+            0 => Layer::Surface,
+            _ => Layer::Underground,
+        }
+    }
+}
+
+/// One band of a zone's column, stacked above or below the surface with a zone of its own
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct VerticalFeature {
-    base_height: i32,
-    width: Box1<i32>,
+    pub band: i32,
+    pub box2: Box2<i32>,
 }
 
 #[derive(Default, Debug)]
 pub struct Schema {
     features: RTree<Feature>,
-    //vertical_features: RTree<VerticalFeature>,
 }
 
 impl Schema {
     pub fn add(&mut self, f: Feature) {
         self.features.insert(f);
-        match f {
-            Feature::GroundBlock(_, _, b) => self.features.insert(Feature::FlatGround(
-                Place::new(b.x.lo_incl, b.y.hi_excl - 1),
-                b.x.size() as u32,
-            )),
-            Feature::HillBlock {
-                start_x,
-                height,
-                lr,
-                ..
-            } => self.features.insert(Feature::SlopedGround {
-                start: Place::new(
-                    start_x,
-                    match lr {
-                        LR::L => height.lo_incl,
-                        LR::R => height.hi_excl - 1,
-                    },
-                ),
-                height: height.size() * -i32::from(lr),
-            }),
-            Feature::BigMushroomTop(p, width) => self.features.insert(Feature::FlatGround(
-                p - Place::new(-(width as i32), 0),
-                width,
-            )),
-            Feature::Igloo { .. }
-            | Feature::Tile(_, _)
-            | Feature::CrateCrossRect(_)
-            | Feature::CrateRandomRect(_)
-            | Feature::SurfaceWater(_)
-            | Feature::SurfaceLava(_)
-            | Feature::BigMushroomStem(_, _)
-            | Feature::SlopedGround { .. }
-            | Feature::FlatGround(_, _)
-            | Feature::Zone(_, _)
-            | Feature::Offscreen(_) => (),
+        if let Some(surface) = f.surface() {
+            self.features.insert(surface);
         }
     }
 
-    // pub fn add_vertical(&mut self, v: VerticalFeature) {
-    //     self.vertical_features.insert(v);
-    // }
+    pub fn remove(&mut self, f: Feature) {
+        self.features.remove(&f);
+        if let Some(surface) = f.surface() {
+            self.features.remove(&surface);
+        }
+    }
 
     pub fn intersecting(&self, b: Box2<i32>) -> Copied<LocateInEnvelopeIntersecting<Feature>> {
         self.features
@@ -301,3 +572,110 @@ impl Schema {
         Box2::new(aabb.lower(), (aabb.upper().0 + 1, aabb.upper().1 + 1))
     }
 }
+
+type Bands = BTreeMap<i32, (VerticalFeature, Schema)>;
+
+/// The level as a sequence of zone columns, keyed by the end of their x range. Each column's
+/// bands are generated independently as they come into view, and kept in band order. The liquid
+/// the bands are generated with is poured into a layer of its own, where it can flow
+#[derive(Default, Debug, Resource)]
+pub struct LevelSchema {
+    zones: BTreeMap<i32, (Box1<i32>, Section, Bands)>,
+    generated: Option<Box1<i32>>,
+    liquid: LiquidLayer,
+    // the noise each band is generated with, see `Gen::for_band`
+    band_gens: BTreeMap<i32, Gen>,
+    acts: Acts,
+}
+
+impl LevelSchema {
+    pub fn section(&mut self, gen: &Gen, index: i32, start: i32) -> Section {
+        section(gen, &mut self.acts, index, start)
+    }
+
+    pub fn band_gen(&mut self, gen: &Gen, band: i32) -> &Gen {
+        self.band_gens
+            .entry(band)
+            .or_insert_with(|| gen.for_band(band))
+    }
+
+    pub fn generated(&self) -> Option<Box1<i32>> {
+        self.generated
+    }
+
+    // zones must be inserted so that the generated range stays contiguous
+    pub fn insert_zone(&mut self, x: Box1<i32>, section: Section) {
+        self.generated = Some(match self.generated {
+            Some(g) => {
+                assert!(g.hi_excl == x.lo_incl || g.lo_incl == x.hi_excl);
+                g.union_cover(x)
+            }
+            None => x,
+        });
+        self.zones.insert(x.hi_excl, (x, section, BTreeMap::new()));
+    }
+
+    // the indices of the sections laid out so far, see `pacing::section`
+    pub fn sections(&self) -> Box1<i32> {
+        match (self.zones.values().next(), self.zones.values().next_back()) {
+            (Some((_, first, _)), Some((_, last, _))) => Box1::new(first.index, last.index + 1),
+            _ => Box1::new(0, 0),
+        }
+    }
+
+    pub fn zones(&self, x: Box1<i32>) -> impl Iterator<Item = (Box1<i32>, Section)> + '_ {
+        self.zones
+            .range(x.lo_incl + 1..)
+            .take_while(move |(_, (bounds, _, _))| bounds.lo_incl < x.hi_excl)
+            .map(|(_, (bounds, section, _))| (*bounds, *section))
+    }
+
+    pub fn has_band(&self, x: Box1<i32>, band: i32) -> bool {
+        self.zones
+            .get(&x.hi_excl)
+            .map_or(false, |(_, _, bands)| bands.contains_key(&band))
+    }
+
+    pub fn insert_band(&mut self, v: VerticalFeature, schema: Schema) {
+        for f in schema.intersecting(v.box2) {
+            if let Feature::SurfaceLiquid(liquid, b) = f {
+                for x in b.x.iter() {
+                    for y in b.y.iter() {
+                        self.liquid.pour(Place::new(x, y), liquid);
+                    }
+                }
+            }
+        }
+        let (_, _, bands) = self.zones.get_mut(&v.box2.x.hi_excl).unwrap();
+        bands.insert(v.band, (v, schema));
+        // liquid already there may have somewhere new to go
+        self.liquid.wake(v.box2);
+    }
+
+    pub fn liquid(&self) -> &LiquidLayer {
+        &self.liquid
+    }
+
+    pub fn liquid_mut(&mut self) -> &mut LiquidLayer {
+        &mut self.liquid
+    }
+
+    pub fn intersecting(&self, b: Box2<i32>) -> impl Iterator<Item = Feature> + '_ {
+        self.zones
+            .range(b.x.lo_incl + 1..)
+            .take_while(move |(_, (bounds, _, _))| bounds.lo_incl < b.x.hi_excl)
+            .flat_map(|(_, (_, _, bands))| bands.values())
+            .flat_map(move |(_, schema)| schema.intersecting(b))
+    }
+
+    // features can reach past their own band, e.g. trees on the surface, so every band is checked
+    pub fn at_point(&self, p: Place) -> impl Iterator<Item = Feature> + '_ {
+        self.zones
+            .range(p.x + 1..)
+            .next()
+            .filter(|(_, (bounds, _, _))| bounds.contains(p.x))
+            .into_iter()
+            .flat_map(|(_, (_, _, bands))| bands.values())
+            .flat_map(move |(_, schema)| schema.at_point(p))
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::helpers::*;
+
+use super::{feature::Liquid, tile::Tile, Place};
+
+// how much liquid a cell holds when full
+pub const FULL: u8 = 8;
+// rows of water this far under the surface are drawn as deep water
+const DEEP_WATER: i32 = 3;
+
+const DOWN: Place = Place::new(0, -1);
+const SIDES: [Place; 2] = [Place::new(-1, 0), Place::new(1, 0)];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub liquid: Liquid,
+    pub level: u8,
+}
+
+// Liquid as a cellular automaton over the cells it's in. Each tick a cell pours as much as fits
+// into the cell under it, then hands a unit to each side that's at least two lower, so bodies
+// fill their basins, spill over their rims and come to rest with no step between neighbours
+// bigger than one. Only cells near a change are looked at, and none are until something stirs
+// them, so the layer knows nothing of terrain beyond what it's asked about through `solid`
+#[derive(Clone, Default, Debug)]
+pub struct LiquidLayer {
+    cells: HashMap<Place, Cell>,
+    active: HashSet<Place>,
+    right_first: bool,
+}
+
+impl LiquidLayer {
+    pub fn get(&self, p: Place) -> Option<Cell> {
+        self.cells.get(&p).copied()
+    }
+
+    // fills `p`, which then flows until it comes to rest
+    pub fn pour(&mut self, p: Place, liquid: Liquid) {
+        self.cells.insert(
+            p,
+            Cell {
+                liquid,
+                level: FULL,
+            },
+        );
+        self.active.insert(p);
+    }
+
+    // stirs the liquid in and around `b`, for after the terrain there has changed
+    pub fn wake(&mut self, b: Box2<i32>) {
+        let around = Box2::new(
+            (b.x.lo_incl - 1, b.y.lo_incl - 1),
+            (b.x.hi_excl + 1, b.y.hi_excl + 1),
+        );
+        self.active.extend(
+            self.cells
+                .keys()
+                .filter(|p| around.contains((p.x, p.y)))
+                .copied(),
+        );
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    // how much of `liquid` the cell at `p` can take
+    fn room(&self, p: Place, liquid: Liquid, solid: &impl Fn(Place) -> bool) -> u8 {
+        if solid(p) {
+            return 0;
+        }
+        match self.cells.get(&p) {
+            None => FULL,
+            Some(c) if c.liquid == liquid => FULL - c.level,
+            Some(_) => 0,
+        }
+    }
+
+    fn level(&self, p: Place) -> u8 {
+        self.cells.get(&p).map_or(0, |c| c.level)
+    }
+
+    fn fill(&mut self, p: Place, liquid: Liquid, amount: u8) {
+        self.cells
+            .entry(p)
+            .or_insert(Cell { liquid, level: 0 })
+            .level += amount;
+    }
+
+    // One step of the flow within `bounds`, bottom row first so falling columns move together.
+    // Liquid outside is held where it is until a tick covers it. Returns the cells that changed,
+    // if any did
+    pub fn tick(&mut self, bounds: Box2<i32>, solid: impl Fn(Place) -> bool) -> Option<Box2<i32>> {
+        let (mut order, held): (Vec<_>, Vec<_>) = self
+            .active
+            .drain()
+            .partition(|p| bounds.contains((p.x, p.y)));
+        self.active.extend(held);
+        order.sort_by_key(|p| (p.y, p.x));
+        // which side goes first swaps every tick, so bodies don't drift one way
+        let sides = if self.right_first {
+            [SIDES[1], SIDES[0]]
+        } else {
+            SIDES
+        };
+        self.right_first = !self.right_first;
+
+        let mut changed = Vec::new();
+        for p in order {
+            let Some(mut cell) = self.get(p) else {
+                continue;
+            };
+            // liquid gets pushed out by ground that's put over it
+            if solid(p) {
+                self.cells.remove(&p);
+                changed.push(p);
+                continue;
+            }
+
+            let mut moved = Vec::new();
+            let below = p + DOWN;
+            let down = self.room(below, cell.liquid, &solid).min(cell.level);
+            if down > 0 {
+                self.fill(below, cell.liquid, down);
+                cell.level -= down;
+                moved.push(below);
+            }
+            for side in sides {
+                let n = p + side;
+                if cell.level > self.level(n) + 1 && self.room(n, cell.liquid, &solid) > 0 {
+                    self.fill(n, cell.liquid, 1);
+                    cell.level -= 1;
+                    moved.push(n);
+                }
+            }
+            if moved.is_empty() {
+                continue;
+            }
+
+            if cell.level == 0 {
+                self.cells.remove(&p);
+            } else {
+                self.cells.insert(p, cell);
+            }
+            changed.push(p);
+            changed.extend(moved);
+        }
+
+        let mut region: Option<Box2<i32>> = None;
+        for p in changed {
+            for n in [p, p + DOWN, p - DOWN, p + SIDES[0], p + SIDES[1]] {
+                if self.cells.contains_key(&n) {
+                    self.active.insert(n);
+                }
+            }
+            let b = Box2::from_point((p.x, p.y));
+            region = Some(region.map_or(b, |r| r.union_cover(b)));
+        }
+        region
+    }
+
+    // Keeps ticking until nothing moves or `max_ticks` have gone by, and gives back everything
+    // that changed on the way
+    pub fn settle(
+        &mut self,
+        bounds: Box2<i32>,
+        solid: impl Fn(Place) -> bool,
+        max_ticks: u32,
+    ) -> Option<Box2<i32>> {
+        let mut region: Option<Box2<i32>> = None;
+        for _ in 0..max_ticks {
+            let Some(b) = self.tick(bounds, &solid) else {
+                break;
+            };
+            region = Some(region.map_or(b, |r| r.union_cover(b)));
+        }
+        region
+    }
+
+    // the tile the liquid at `p` is drawn with: waves along the top, a single-column top where
+    // there's no liquid either side, and deep water well under the surface
+    pub fn tile_at(&self, p: Place, alt: bool) -> Option<Tile> {
+        let cell = self.get(p)?;
+        let same = |q: Place| self.get(q).map_or(false, |c| c.liquid == cell.liquid);
+        Some(if !same(p - DOWN) {
+            if SIDES.iter().any(|&s| same(p + s)) {
+                cell.liquid.wave()
+            } else {
+                cell.liquid.single()
+            }
+        } else if cell.liquid == Liquid::Water && (1..=DEEP_WATER).all(|d| same(p - DOWN * d)) {
+            Tile::DeepWater(alt)
+        } else {
+            cell.liquid.body()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: Box2<i32> = Box2 {
+        x: Box1 {
+            lo_incl: -10,
+            hi_excl: 10,
+        },
+        y: Box1 {
+            lo_incl: -10,
+            hi_excl: 10,
+        },
+    };
+
+    fn total(layer: &LiquidLayer) -> u32 {
+        layer.cells.values().map(|c| c.level as u32).sum()
+    }
+
+    #[test]
+    fn falls_to_the_floor() {
+        // a one wide shaft
+        let solid = |p: Place| p.y <= 0 || p.x != 0;
+        let mut layer = LiquidLayer::default();
+        layer.pour(Place::new(0, 5), Liquid::Water);
+        layer.settle(BOUNDS, solid, 100);
+
+        assert_eq!(
+            layer.get(Place::new(0, 1)),
+            Some(Cell {
+                liquid: Liquid::Water,
+                level: FULL,
+            })
+        );
+        assert_eq!(layer.cells.len(), 1);
+    }
+
+    #[test]
+    fn spreads_along_the_floor() {
+        let solid = |p: Place| p.y <= 0 || p.x.abs() > 3;
+        let mut layer = LiquidLayer::default();
+        layer.pour(Place::new(0, 1), Liquid::Water);
+        layer.settle(BOUNDS, solid, 100);
+
+        assert_eq!(total(&layer), FULL as u32);
+        for x in -3..3 {
+            let (a, b) = (
+                layer.level(Place::new(x, 1)),
+                layer.level(Place::new(x + 1, 1)),
+            );
+            assert!(a.abs_diff(b) <= 1, "{x}: {a} next to {b}");
+        }
+        assert!(layer.level(Place::new(-1, 1)) > 0 && layer.level(Place::new(1, 1)) > 0);
+    }
+
+    #[test]
+    fn comes_to_rest() {
+        let solid = |p: Place| p.y <= 0 || p.x.abs() > 3;
+        let mut layer = LiquidLayer::default();
+        layer.pour(Place::new(0, 3), Liquid::Water);
+        assert!(!layer.is_settled());
+        assert!(layer.settle(BOUNDS, solid, 100).is_some());
+
+        assert!(layer.is_settled());
+        assert_eq!(layer.settle(BOUNDS, solid, 100), None);
+    }
+
+    #[test]
+    fn spills_over_when_the_dam_goes() {
+        // a pool at x = 0 held in by a dam at x = 1, with the floor running on past it
+        let dam = |p: Place| p.x == 1 && p.y < 4;
+        let floor = |p: Place| p.y <= 0 || p.x < 0 || p.x > 6;
+        let mut layer = LiquidLayer::default();
+        for y in 1..3 {
+            layer.pour(Place::new(0, y), Liquid::Water);
+        }
+        layer.settle(BOUNDS, |p| floor(p) || dam(p), 100);
+        assert_eq!(layer.level(Place::new(0, 2)), FULL);
+
+        // nothing moves until the liquid is told the terrain changed
+        assert_eq!(layer.settle(BOUNDS, floor, 100), None);
+        layer.wake(Box2::new((1, 1), (2, 4)));
+        layer.settle(BOUNDS, floor, 100);
+
+        assert_eq!(total(&layer), 2 * FULL as u32);
+        assert_eq!(layer.level(Place::new(0, 2)), 0);
+        assert!((1..4).all(|x| layer.level(Place::new(x, 1)) > 0));
+    }
+
+    #[test]
+    fn waits_outside_the_bounds() {
+        let solid = |p: Place| p.y <= 0;
+        let mut layer = LiquidLayer::default();
+        layer.pour(Place::new(20, 5), Liquid::Water);
+        assert_eq!(layer.settle(BOUNDS, solid, 100), None);
+        assert_eq!(layer.level(Place::new(20, 5)), FULL);
+        assert!(!layer.is_settled());
+
+        layer.settle(Box2::new((10, -10), (30, 10)), solid, 100);
+        assert_eq!(layer.level(Place::new(20, 5)), 0);
+        assert_eq!(total(&layer), FULL as u32);
+    }
+}
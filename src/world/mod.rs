@@ -1,8 +1,14 @@
+pub mod autotile;
 pub mod brushes;
 pub mod feature;
+pub mod liquid;
+pub mod nav;
+pub mod pacing;
 pub mod physics;
 pub mod player;
 pub mod tile;
+pub mod tileset;
+pub mod wfc;
 
 use bevy::{
     math::{IVec2, Rect, Vec3},
@@ -17,13 +23,14 @@ use bevy_ecs_tilemap::{
     tiles::{TileBundle, TilePos, TileStorage, TileTextureIndex},
     TilemapBundle,
 };
+use bevy_rapier2d::prelude::Sensor;
 use brushes::*;
 use noise::NoiseFn;
 use std::collections::HashSet;
 
-use self::{feature::*, physics::collider_for, tile::*};
+use self::{autotile::TilingRules, feature::*, physics::collider_for, tile::*, tileset::Tileset};
 use crate::{
-    assets::{SpriteAssets, PIXEL_MODEL_TRANSFORM, SHEET_W, TILE_SIZE},
+    assets::{SpriteAssets, TilesetAsset, TilingRulesAsset, PIXEL_MODEL_TRANSFORM, TILE_SIZE},
     camera::{get_camera_rect, LetterboxProjection, SofiaCamera},
     helpers::*,
     //map::brushes::*,
@@ -46,8 +53,13 @@ pub fn intersect(r: Rect) -> impl Iterator<Item = Place> {
         .map(|(a, b)| Place::new(a, b))
 }
 
+// the chunk's place in the chunk grid, which its transform doesn't give
 #[derive(Component, Clone, Copy, Debug)]
-pub struct Chunk;
+pub struct Chunk(pub Place);
+
+// the chunk's tilemaps, one for each level `render_level` draws, from the bottom up
+#[derive(Component, Clone, Debug)]
+pub struct ChunkLevels(pub Vec<Entity>);
 
 #[derive(Copy, Clone, Component, PartialEq, Eq, Debug, Hash)]
 pub struct LevelEntity;
@@ -70,7 +82,7 @@ fn debug_load_chunk(
     chunk_place: Place,
 ) {
     let chunk = commands
-        .spawn(Chunk)
+        .spawn(Chunk(chunk_place))
         .insert(SpatialBundle::from_transform(Transform::from_translation(
             Vec3::new(
                 chunk_place.x as f32 * CHUNK_SIZE as f32,
@@ -236,28 +248,19 @@ fn load_chunk(
     commands: &mut Commands,
     level: Res<LevelResource>,
     res_gen: Res<Gen>,
+    schema: &LevelSchema,
     sa: Res<SpriteAssets>,
+    rules: &TilingRules,
+    tileset: &Tileset,
     chunk_place: Place,
 ) {
-    let schema = generate_level(&res_gen);
-    let bounds = Box2::from_box1s(
-        Box1::new(
-            chunk_place.x * CHUNK_SIZE as i32,
-            (chunk_place.x + 1) * CHUNK_SIZE as i32,
-        ),
-        Box1::new(
-            chunk_place.y * CHUNK_SIZE as i32,
-            (chunk_place.y + 1) * CHUNK_SIZE as i32,
-        ),
-    );
-
-    let place_vec = Vec2::new(
-        chunk_place.x as f32 * CHUNK_SIZE as f32,
-        chunk_place.y as f32 * CHUNK_SIZE as f32,
-    );
+    let bounds = chunk_bounds(chunk_place);
 
-    let v = render_level(&schema, &res_gen, bounds);
-    let chunk = commands.spawn(Chunk).insert(SpatialBundle::default()).id();
+    let v = render_level(schema, &res_gen, rules, bounds);
+    let chunk = commands
+        .spawn(Chunk(chunk_place))
+        .insert(SpatialBundle::default())
+        .id();
     //.insert(RigidBody::Fixed)
     commands.entity(level.0).add_child(chunk);
 
@@ -293,43 +296,84 @@ fn load_chunk(
         }
     }
 
-    let mut storage_and_entity = Vec::with_capacity(v.dim().2);
-    for _ in 0..v.dim().2 {
-        let storage = TileStorage::empty(TilemapSize {
+    let mut levels = ChunkLevels(Vec::new());
+    add_levels(commands, chunk, &mut levels, chunk_place, &v, tileset, &sa);
+    commands.entity(chunk).insert(levels);
+
+    for f in schema.intersecting(bounds) {
+        if let Some(collider) = collider_for(f) {
+            let collider = commands
+                .spawn(collider)
+                .insert(TransformBundle::from_transform(
+                    Transform::from_translation(f.bounds().center().extend(0.0)),
+                ))
+                .id();
+            // coins are collected rather than stood on
+            if let Feature::Coin(..) = f {
+                commands.entity(collider).insert(Sensor);
+            }
+            commands.entity(chunk).add_child(collider);
+        }
+    }
+}
+
+fn spawn_tile(commands: &mut Commands, tilemap: Entity, position: TilePos, index: u32) -> Entity {
+    let tile = commands
+        .spawn(TileBundle {
+            position,
+            texture_index: TileTextureIndex(index),
+            tilemap_id: TilemapId(tilemap),
+            ..Default::default()
+        })
+        .id();
+    commands.entity(tilemap).add_child(tile);
+    tile
+}
+
+// the cell a tile is drawn with, if it's drawn at all. Tiles the manifest has no cell for were
+// warned about when it loaded
+fn texture_index(tileset: &Tileset, t: Tile) -> Option<u32> {
+    match t {
+        Tile::Air => None,
+        _ => tileset.texture_index(t),
+    }
+}
+
+// Gives the chunk tilemaps for the levels of `v` it doesn't have yet, with their tiles
+fn add_levels(
+    commands: &mut Commands,
+    chunk: Entity,
+    levels: &mut ChunkLevels,
+    chunk_place: Place,
+    v: &ndarray::Array3<Tile>,
+    tileset: &Tileset,
+    sa: &SpriteAssets,
+) {
+    let place_vec = Vec2::new(
+        chunk_place.x as f32 * CHUNK_SIZE as f32,
+        chunk_place.y as f32 * CHUNK_SIZE as f32,
+    );
+
+    for k in levels.0.len()..v.dim().2 {
+        let mut storage = TileStorage::empty(TilemapSize {
             x: v.dim().0 as u32,
             y: v.dim().1 as u32,
         });
         let tilemap_entity = commands.spawn_empty().id();
-        storage_and_entity.push((storage, tilemap_entity));
         commands.entity(chunk).add_child(tilemap_entity);
-    }
 
-    for ((i, j, k), &t) in v.indexed_iter() {
-        if t == Tile::Air {
-            continue;
+        for ((i, j), &t) in v.index_axis(ndarray::Axis(2), k).indexed_iter() {
+            let Some(index) = texture_index(tileset, t) else {
+                continue;
+            };
+            let tile_pos = TilePos {
+                x: i as u32,
+                y: j as u32,
+            };
+            let tile_entity = spawn_tile(commands, tilemap_entity, tile_pos, index);
+            storage.set(&tile_pos, tile_entity);
         }
 
-        let tile_pos = TilePos {
-            x: i as u32,
-            y: j as u32,
-        };
-        let (ix, iy) = t.into();
-        let tile_entity = commands
-            .spawn(TileBundle {
-                position: tile_pos,
-                texture_index: TileTextureIndex((iy * SHEET_W + ix) as u32),
-                tilemap_id: TilemapId(storage_and_entity[k].1),
-                ..Default::default()
-            })
-            .id();
-
-        storage_and_entity[k].0.set(&tile_pos, tile_entity);
-        commands
-            .entity(storage_and_entity[k].1)
-            .add_child(tile_entity);
-    }
-
-    for (k, (storage, tilemap_entity)) in storage_and_entity.into_iter().enumerate() {
         commands.entity(tilemap_entity).insert(TilemapBundle {
             grid_size: TilemapGridSize {
                 x: TILE_SIZE as f32,
@@ -348,54 +392,184 @@ fn load_chunk(
             ) * PIXEL_MODEL_TRANSFORM,
             ..Default::default()
         });
-    }
-
-    for f in schema.intersecting(bounds) {
-        if let Some(collider) = collider_for(f) {
-            let collider = commands
-                .spawn(collider)
-                .insert(TransformBundle::from_transform(
-                    Transform::from_translation(f.bounds().center().extend(0.0)),
-                ))
-                .id();
-            commands.entity(chunk).add_child(collider);
-        }
+        levels.0.push(tilemap_entity);
     }
 }
 
 pub fn chunk_loader(
     mut commands: Commands,
-    mut chunks: Query<(Entity, &Transform), With<Chunk>>,
+    chunks: Query<(Entity, &Chunk)>,
     level: Res<LevelResource>,
     res_gen: Res<Gen>,
+    mut schema: ResMut<LevelSchema>,
     sa: Res<SpriteAssets>,
+    tiling_rules: Res<Assets<TilingRulesAsset>>,
+    default_rules: Local<TilingRules>,
+    tilesets: Res<Assets<TilesetAsset>>,
+    default_tileset: Local<Tileset>,
     views: Query<(&Transform, &LetterboxProjection), With<SofiaCamera>>,
 ) {
+    // until the rules file has loaded the ones built in are used
+    let rules = tiling_rules
+        .get(&sa.tiling_rules)
+        .map_or(&*default_rules, |r| &r.0);
+    let tileset = tilesets
+        .get(&sa.tileset)
+        .map_or(&*default_tileset, |t| &t.0);
     for view in views.iter() {
         let mut visible: HashSet<Place> =
             HashSet::from_iter(intersect(get_camera_rect(view.0, view.1)));
 
-        for c in chunks.iter_mut() {
-            let place = Place::new(
-                (c.1.translation.x / (CHUNK_SIZE as f32)) as i32,
-                (c.1.translation.y / (CHUNK_SIZE as f32)) as i32,
-            );
+        for (entity, &Chunk(place)) in chunks.iter() {
             if visible.contains(&place) {
                 visible.remove(&place);
             } else {
                 // unload
-                commands.entity(c.0).despawn_recursive();
+                commands.entity(entity).despawn_recursive();
             }
         }
 
         for &c in visible.iter() {
+            // rendering looks one tile past the chunk edge for tiling
+            generate_level(
+                &res_gen,
+                &mut schema,
+                Box2::new(
+                    (c.x * CHUNK_SIZE as i32 - 1, c.y * CHUNK_SIZE as i32 - 1),
+                    (
+                        (c.x + 1) * CHUNK_SIZE as i32 + 1,
+                        (c.y + 1) * CHUNK_SIZE as i32 + 1,
+                    ),
+                ),
+            );
             load_chunk(
                 &mut commands,
                 Res::clone(&level),
                 Res::clone(&res_gen),
+                &schema,
                 Res::clone(&sa),
+                rules,
+                tileset,
                 c,
             );
         }
     }
 }
+
+// seconds between steps of the liquid flow
+const LIQUID_TICK: f32 = 0.1;
+
+// Steps the liquid over the loaded chunks, and redraws the tiles it changed where they stand.
+// Liquid in chunks that aren't loaded waits until they are
+pub fn liquid_flow(
+    mut commands: Commands,
+    mut chunks: Query<(Entity, &Chunk, &mut ChunkLevels)>,
+    mut tilemaps: Query<&mut TileStorage>,
+    mut tiles: Query<&mut TileTextureIndex>,
+    res_gen: Res<Gen>,
+    mut schema: ResMut<LevelSchema>,
+    sa: Res<SpriteAssets>,
+    tiling_rules: Res<Assets<TilingRulesAsset>>,
+    default_rules: Local<TilingRules>,
+    tilesets: Res<Assets<TilesetAsset>>,
+    default_tileset: Local<Tileset>,
+    time: Res<Time>,
+    mut since: Local<f32>,
+) {
+    *since += time.delta_seconds();
+    if *since < LIQUID_TICK || schema.liquid().is_settled() {
+        return;
+    }
+    *since = 0.0;
+    let Some(loaded) = chunks
+        .iter()
+        .map(|(_, &Chunk(place), _)| chunk_bounds(place))
+        .reduce(Box2::union_cover)
+    else {
+        return;
+    };
+
+    // the layer is taken out while it ticks so the terrain can be looked up alongside it
+    let mut liquid = std::mem::take(schema.liquid_mut());
+    let changed = liquid.tick(loaded, |p| is_solid(&schema, &res_gen, p));
+    *schema.liquid_mut() = liquid;
+    let Some(changed) = changed else { return };
+
+    let rules = tiling_rules
+        .get(&sa.tiling_rules)
+        .map_or(&*default_rules, |r| &r.0);
+    let tileset = tilesets
+        .get(&sa.tileset)
+        .map_or(&*default_tileset, |t| &t.0);
+    // tiling looks one tile past the cells that changed
+    let redraw = Box2::new(
+        (changed.x.lo_incl - 1, changed.y.lo_incl - 1),
+        (changed.x.hi_excl + 1, changed.y.hi_excl + 1),
+    );
+    for (chunk, &Chunk(place), mut levels) in chunks.iter_mut() {
+        let bounds = chunk_bounds(place);
+        if !bounds.intersects(redraw) {
+            continue;
+        }
+
+        // a level taken up or let go of by the liquid shifts the ones above it, so the whole
+        // chunk is gone over, only touching the tiles that differ
+        let v = render_level(&schema, &res_gen, rules, bounds);
+        for ((i, j, k), &t) in v.indexed_iter() {
+            let Some(&tilemap) = levels.0.get(k) else {
+                continue;
+            };
+            let Ok(mut storage) = tilemaps.get_mut(tilemap) else {
+                continue;
+            };
+            let tile_pos = TilePos {
+                x: i as u32,
+                y: j as u32,
+            };
+            match (storage.get(&tile_pos), texture_index(tileset, t)) {
+                (Some(tile), Some(index)) => {
+                    if let Ok(mut drawn) = tiles.get_mut(tile) && drawn.0 != index {
+                        drawn.0 = index;
+                    }
+                }
+                (Some(tile), None) => {
+                    commands.entity(tile).despawn_recursive();
+                    storage.remove(&tile_pos);
+                }
+                (None, Some(index)) => {
+                    let tile = spawn_tile(&mut commands, tilemap, tile_pos, index);
+                    storage.set(&tile_pos, tile);
+                }
+                (None, None) => (),
+            }
+        }
+        // the levels past the new top are emptied rather than dropped
+        for &tilemap in levels.0.iter().skip(v.dim().2) {
+            let Ok(mut storage) = tilemaps.get_mut(tilemap) else {
+                continue;
+            };
+            for (x, y) in itertools::iproduct!(0..storage.size.x, 0..storage.size.y) {
+                let tile_pos = TilePos { x, y };
+                if let Some(tile) = storage.get(&tile_pos) {
+                    commands.entity(tile).despawn_recursive();
+                    storage.remove(&tile_pos);
+                }
+            }
+        }
+        add_levels(&mut commands, chunk, &mut levels, place, &v, tileset, &sa);
+    }
+}
+
+// the tiles a chunk draws
+fn chunk_bounds(chunk_place: Place) -> Box2<i32> {
+    Box2::new(
+        (
+            chunk_place.x * CHUNK_SIZE as i32,
+            chunk_place.y * CHUNK_SIZE as i32,
+        ),
+        (
+            (chunk_place.x + 1) * CHUNK_SIZE as i32,
+            (chunk_place.y + 1) * CHUNK_SIZE as i32,
+        ),
+    )
+}
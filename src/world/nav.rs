@@ -0,0 +1,198 @@
+use itertools::iproduct;
+use std::collections::HashMap;
+
+use petgraph::{
+    graph::{DiGraph, NodeIndex},
+    visit::{Bfs, Walker},
+};
+
+use crate::helpers::*;
+
+use super::{feature::*, tile::*, Place};
+
+// Level-design limits on the jump the generator lays levels out for, in tiles. They aren't
+// measured from the player: `keyboard_input_system` in player.rs only pushes sideways and `setup`
+// in main.rs turns gravity off, so there is no jump to measure yet. When the controller gets one,
+// its height and the distance to its apex should be worked out from its impulse and gravity and
+// these brought into line with them
+pub const DESIGN_JUMP_HEIGHT: i32 = 4;
+pub const DESIGN_JUMP_APEX: i32 = 3;
+// past this a jump has dropped below anything in the band
+const MAX_REACH: i32 = 12;
+
+// the highest a jump can land `dx` columns over, relative to where it took off. The arc is a
+// parabola, so falls drift further the further down they go
+fn arc(dx: i32) -> f64 {
+    let over = (dx.abs() - DESIGN_JUMP_APEX).max(0) as f64 / DESIGN_JUMP_APEX as f64;
+    DESIGN_JUMP_HEIGHT as f64 * (1.0 - over * over)
+}
+
+pub fn can_jump(dx: i32, dy: i32) -> bool {
+    dy as f64 <= arc(dx)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Move {
+    Walk,
+    Jump,
+    Fall,
+}
+
+// a stretch of ground that can be walked along, as the cells that are stood on
+#[derive(Clone, Debug)]
+pub struct Surface {
+    cells: Vec<Place>,
+    span: Box1<i32>,
+}
+
+impl Surface {
+    fn new(cells: Vec<Place>) -> Self {
+        let span = Box1::new(
+            cells.iter().map(|p| p.x).min().unwrap(),
+            cells.iter().map(|p| p.x).max().unwrap() + 1,
+        );
+        Self { cells, span }
+    }
+
+    fn of(f: Feature) -> Option<Self> {
+        match f {
+            Feature::FlatGround(p, width) => Some(Self::new(
+                (0..width as i32).map(|i| p + Place::new(i, 0)).collect(),
+            )),
+            Feature::SlopedGround { start, height } => Some(Self::new(
+                (0..height.abs())
+                    .map(|i| start + Place::new(i, i * height.signum()))
+                    .collect(),
+            )),
+            // half tile platforms and bridges don't come with surfaces of their own
+            Feature::Tile(
+                p,
+                Tile::Bridge
+                | Tile::Terrain(_, TerrainTile::Half(_, _) | TerrainTile::SingleHalf(_)),
+            ) => Some(Self::new(vec![p])),
+            _ => None,
+        }
+    }
+
+    // the best way from here to `other`, ignoring anything in the way other than the two
+    // surfaces themselves
+    fn move_to(&self, other: &Surface) -> Option<Move> {
+        iproduct!(self.cells.iter(), other.cells.iter())
+            .filter(|(a, b)| {
+                let (dx, dy) = (b.x - a.x, b.y - a.y);
+                // jumping up under the other surface or falling through this one bumps into it
+                can_jump(dx, dy)
+                    && !(dy > 0 && other.span.contains(a.x))
+                    && !(dy < 0 && self.span.contains(b.x))
+            })
+            .map(|(a, b)| {
+                let (dx, dy) = (b.x - a.x, b.y - a.y);
+                if dx.abs() <= 1 && dy.abs() <= 1 {
+                    Move::Walk
+                } else if dy < 0 {
+                    Move::Fall
+                } else {
+                    Move::Jump
+                }
+            })
+            .min_by_key(|&m| m as u8)
+    }
+}
+
+// Surfaces and the jumps and falls between them
+pub struct NavGraph {
+    graph: DiGraph<Surface, Move>,
+    cells: HashMap<Place, NodeIndex>,
+}
+
+impl NavGraph {
+    pub fn new(features: impl IntoIterator<Item = Feature>) -> Self {
+        let features: Vec<_> = features.into_iter().collect();
+        // flat ground that a hill slope runs over is buried under it, so only the stretches either
+        // side of the slope can be stood on
+        let slopes: Vec<_> = features
+            .iter()
+            .filter(|f| matches!(f, Feature::SlopedGround { .. }))
+            .filter_map(|&f| Surface::of(f))
+            .flat_map(|s| s.cells)
+            .collect();
+        let covered = |c: &Place| slopes.iter().any(|s| s.x == c.x && s.y > c.y);
+
+        let mut graph = DiGraph::new();
+        let mut cells = HashMap::new();
+        for s in features.into_iter().filter_map(Surface::of) {
+            for run in s.cells.split(covered).filter(|r| !r.is_empty()) {
+                let n = graph.add_node(Surface::new(run.to_vec()));
+                for &c in run {
+                    cells.insert(c, n);
+                }
+            }
+        }
+
+        let nodes: Vec<_> = graph.node_indices().collect();
+        for (&a, &b) in iproduct!(nodes.iter(), nodes.iter()) {
+            let (sa, sb) = (&graph[a], &graph[b]);
+            let near = Box1::new(sa.span.lo_incl - MAX_REACH, sa.span.hi_excl + MAX_REACH);
+            if a == b || !near.intersects(sb.span) {
+                continue;
+            }
+            if let Some(m) = sa.move_to(sb) {
+                graph.add_edge(a, b, m);
+            }
+        }
+
+        Self { graph, cells }
+    }
+
+    // the highest surface cell at or under `p`
+    fn under(&self, p: Place) -> Option<NodeIndex> {
+        self.cells
+            .iter()
+            .filter(|(c, _)| c.x == p.x && c.y <= p.y)
+            .max_by_key(|(c, _)| c.y)
+            .map(|(_, &n)| n)
+    }
+
+    // the leftmost and rightmost cells, the lowest where there's a choice
+    pub fn ends(&self) -> Option<(Place, Place)> {
+        let left = self.cells.keys().min_by_key(|p| (p.x, p.y))?;
+        let right = self.cells.keys().max_by_key(|p| (p.x, -p.y))?;
+        Some((*left, *right))
+    }
+
+    // Whether the ground under `to` can be got to from the ground under `from`. If it can't,
+    // gives the closest the player gets, and where past that they would need to land. With no
+    // ground under `from` that's the whole way, and with nothing past the furthest the player
+    // gets it's the way from the closest they get to `to` itself
+    pub fn check(&self, from: Place, to: Place) -> Result<(), (Place, Place)> {
+        let Some(start) = self.under(from) else {
+            return Err((from, to));
+        };
+        let reached: Vec<_> = Bfs::new(&self.graph, start).iter(&self.graph).collect();
+        if self.under(to).map_or(false, |end| reached.contains(&end)) {
+            return Ok(());
+        }
+
+        let reached_cells: Vec<_> = reached
+            .iter()
+            .flat_map(|&n| self.graph[n].cells.iter().copied())
+            .collect();
+        let furthest = reached_cells.iter().map(|p| p.x).max().unwrap();
+        let beyond = self
+            .graph
+            .node_indices()
+            .filter(|n| !reached.contains(n))
+            .flat_map(|n| self.graph[n].cells.iter().copied())
+            .filter(|p| p.x > furthest);
+        let distance = |a: Place, b: Place| (b.x - a.x).abs() + (b.y - a.y).abs();
+        Err(iproduct!(reached_cells.iter().copied(), beyond)
+            .min_by_key(|&(a, b)| (distance(a, b), b.x, b.y))
+            .unwrap_or_else(|| {
+                let closest = reached_cells
+                    .iter()
+                    .copied()
+                    .min_by_key(|&a| distance(a, to));
+                (closest.unwrap(), to)
+            }))
+    }
+}
@@ -0,0 +1,261 @@
+use noise::NoiseFn;
+
+use crate::helpers::*;
+
+use super::{brushes::Gen, feature::*};
+
+// the beats a level is paced in, each of them one surface zone
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Beat {
+    Intro,
+    Challenge,
+    Rest,
+    Climax,
+    Castle,
+}
+
+impl Beat {
+    fn zones(self) -> &'static [Zone] {
+        match self {
+            Beat::Intro => &[
+                Zone::Grass(Zone1::Plains),
+                Zone::Forest,
+                Zone::Desert(Zone1::Plains),
+            ],
+            Beat::Rest => &[
+                Zone::Forest,
+                Zone::Grass(Zone1::Plains),
+                Zone::Grass(Zone1::Lake),
+                Zone::Desert(Zone1::Plains),
+                Zone::SnowForest,
+            ],
+            Beat::Challenge => &[
+                Zone::Grass(Zone1::Hills),
+                Zone::Desert(Zone1::Hills),
+                Zone::Desert(Zone1::Lake),
+                Zone::Candy(Zone1::Plains),
+                Zone::Candy(Zone1::Hills),
+                Zone::Candy(Zone1::Lake),
+                Zone::Factory(Zone1::Plains),
+                Zone::Factory(Zone1::Lake),
+                Zone::Mushroom,
+                Zone::SnowForest,
+                Zone::StoneMountain,
+            ],
+            Beat::Climax => &[
+                Zone::Factory(Zone1::Hills),
+                Zone::LavaHills,
+                Zone::StoneMountain,
+                Zone::Mushroom,
+                Zone::LavaPlains,
+            ],
+            Beat::Castle => &[Zone::Castle],
+        }
+    }
+
+    fn widths(self) -> Box1<i32> {
+        match self {
+            Beat::Intro => Box1::new(30, 50),
+            Beat::Challenge => Box1::new(30, 60),
+            Beat::Rest => Box1::new(20, 35),
+            Beat::Climax => Box1::new(50, 70),
+            Beat::Castle => Box1::new(30, 50),
+        }
+    }
+}
+
+// The grammar's nonterminals. An act opens gently, builds up through rounds of challenges with
+// rests between them, and ends in a climax and a castle:
+//   Act   -> (Intro | Rest) Build Climax Castle
+//   Build -> Challenge Rest | Challenge Challenge Rest | Challenge Rest Build
+#[derive(Clone, Copy, Debug)]
+enum Symbol {
+    Beat(Beat),
+    Act { first: bool },
+    Build { depth: u32 },
+}
+
+const MAX_BUILD_DEPTH: u32 = 3;
+
+fn expand(gen: &Gen, act: i32, symbol: Symbol, out: &mut Vec<Beat>) {
+    let rule: &[Symbol] = match symbol {
+        Symbol::Beat(beat) => return out.push(beat),
+        Symbol::Act { first } => &[
+            Symbol::Beat(if first { Beat::Intro } else { Beat::Rest }),
+            Symbol::Build { depth: 0 },
+            Symbol::Beat(Beat::Climax),
+            Symbol::Beat(Beat::Castle),
+        ],
+        Symbol::Build { depth } => {
+            let n = gen.theme.get([act as f64, 67.0 + depth as f64]);
+            if n < 0.4 || depth + 1 == MAX_BUILD_DEPTH {
+                &[Symbol::Beat(Beat::Challenge), Symbol::Beat(Beat::Rest)]
+            } else if n < 0.7 {
+                &[
+                    Symbol::Beat(Beat::Challenge),
+                    Symbol::Beat(Beat::Challenge),
+                    Symbol::Beat(Beat::Rest),
+                ]
+            } else {
+                &[
+                    Symbol::Beat(Beat::Challenge),
+                    Symbol::Beat(Beat::Rest),
+                    Symbol::Build { depth: depth + 1 },
+                ]
+            }
+        }
+    };
+    for &s in rule {
+        expand(gen, act, s, out);
+    }
+}
+
+// The acts expanded so far on each side of the spawn, each with the number of sections up to and
+// including it, so sections further out don't need the acts before them expanded again
+#[derive(Default, Debug)]
+pub struct Acts {
+    right: Vec<(usize, Vec<Beat>)>,
+    left: Vec<(usize, Vec<Beat>)>,
+}
+
+impl Acts {
+    // the act that the `k`th section out on a side falls in, and where in the act it is
+    fn locate(&mut self, gen: &Gen, step: i32, k: usize) -> (&[Beat], usize) {
+        let side = if step > 0 {
+            &mut self.right
+        } else {
+            &mut self.left
+        };
+        while side.last().map_or(true, |&(end, _)| end <= k) {
+            let act = if step > 0 {
+                side.len() as i32
+            } else {
+                -(side.len() as i32) - 1
+            };
+            let mut beats = Vec::new();
+            expand(
+                gen,
+                act,
+                Symbol::Act {
+                    first: side.is_empty(),
+                },
+                &mut beats,
+            );
+            let end = side.last().map_or(0, |&(end, _)| end) + beats.len();
+            side.push((end, beats));
+        }
+        let i = side.partition_point(|&(end, _)| end <= k);
+        let start = if i == 0 { 0 } else { side[i - 1].0 };
+        (&side[i].1, k - start)
+    }
+}
+
+// How hard the level gets with distance from the spawn: it holds at `start` across the
+// onboarding stretch either side, then eases up to full difficulty over the next `ramp` tiles
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DifficultyCurve {
+    pub onboarding: i32,
+    pub ramp: i32,
+    pub start: f64,
+}
+
+impl DifficultyCurve {
+    pub fn at(self, x: i32) -> f64 {
+        let t = ((x.abs() - self.onboarding) as f64 / self.ramp.max(1) as f64).clamp(0.0, 1.0);
+        self.start + (1.0 - self.start) * t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Default for DifficultyCurve {
+    fn default() -> Self {
+        Self {
+            onboarding: 120,
+            ramp: 800,
+            start: 0.2,
+        }
+    }
+}
+
+// one surface zone's place in the level's structure
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Section {
+    pub index: i32,
+    pub beat: Beat,
+    pub zone: Zone,
+    pub width: i32,
+    // from 0 for the gentlest stretch to 1 for the hardest
+    pub difficulty: f64,
+}
+
+// Picks one of `zones` by `n`, leaving out those too hard for `level` unless none is easy
+// enough, in which case the gentlest goes in
+pub fn pick_zone(n: f64, zones: impl Iterator<Item = Zone> + Clone, level: f64) -> Zone {
+    let choices: Vec<_> = zones
+        .clone()
+        .filter(|z| z.info().min_difficulty <= level)
+        .collect();
+    if choices.is_empty() {
+        zones
+            .min_by(|a, b| a.info().min_difficulty.total_cmp(&b.info().min_difficulty))
+            .unwrap()
+    } else {
+        n_to_slice(n, &choices)
+    }
+}
+
+// The level reads outwards from the spawn both ways: sections 0, 1.. to the right and -1, -2..
+// to the left, each side a chain of acts of its own that starts with an intro. Every section is
+// worked out from its index and the edge it starts from on the spawn's side, so it doesn't matter
+// which are asked for first
+pub fn section(gen: &Gen, acts: &mut Acts, index: i32, start: i32) -> Section {
+    let step = if index >= 0 { 1 } else { -1 };
+    let (beats, k) = acts.locate(
+        gen,
+        step,
+        if index >= 0 { index } else { -index - 1 } as usize,
+    );
+
+    let beat = beats[k];
+    let width = |j: usize| {
+        let i = index - step * (k - j) as i32;
+        n_to_box1(gen.theme.get([i as f64, 71.0]), beats[j].widths())
+    };
+    // where each section of the act so far starts, working back from this one
+    let mut starts = vec![start; k + 1];
+    for j in (0..k).rev() {
+        starts[j] = starts[j + 1] - step * width(j);
+    }
+
+    // challenges get harder through the act, the other beats set the rhythm around them, and
+    // the whole lot is scaled down near the spawn
+    let pacing = match beat {
+        Beat::Intro => 0.1,
+        Beat::Rest => 0.25,
+        Beat::Challenge => {
+            let before = beats[..k].iter().filter(|&&b| b == Beat::Challenge).count();
+            (0.4 + 0.1 * before as f64).min(0.8)
+        }
+        Beat::Climax => 0.9,
+        Beat::Castle => 0.6,
+    };
+    let difficulty = pacing * gen.difficulty.at(start);
+
+    // neighbouring sections never share a zone. Acts start just after a castle, so looking back
+    // through the act is enough
+    let mut zone = None;
+    for (j, b) in beats[..=k].iter().enumerate() {
+        let n = gen
+            .theme
+            .get([(index - step * (k - j) as i32) as f64, 70.0]);
+        let fresh = b.zones().iter().copied().filter(|&z| Some(z) != zone);
+        zone = Some(pick_zone(n, fresh, gen.difficulty.at(starts[j])));
+    }
+
+    Section {
+        index,
+        beat,
+        zone: zone.unwrap(),
+        width: width(k),
+        difficulty,
+    }
+}
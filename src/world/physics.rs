@@ -1,18 +1,170 @@
+use bevy::prelude::Vec2;
 use bevy_rapier2d::prelude::Collider;
 
-use super::feature::*;
+use super::{feature::*, tile::*};
+use crate::helpers::*;
+
+// a cuboid covering part of a feature, placed relative to the feature's centre
+fn part(outer: Box2<i32>, inner: Box2<i32>) -> (Vec2, f32, Collider) {
+    (
+        inner.center() - outer.center(),
+        0.0,
+        Collider::cuboid(inner.x.size() as f32 / 2.0, inner.y.size() as f32 / 2.0),
+    )
+}
 
 pub fn collider_for(f: Feature) -> Option<Collider> {
     match f {
+        // only the part under the diagonal is solid
+        Feature::Tile(_, Tile::Terrain(_, TerrainTile::Slope(lr))) => {
+            let high = Vec2::new(i32::from(lr) as f32 * -0.5, 0.5);
+            Some(Collider::triangle(
+                Vec2::new(-0.5, -0.5),
+                Vec2::new(0.5, -0.5),
+                high,
+            ))
+        }
+        // half tiles are only solid in their top half
+        Feature::Tile(_, Tile::Terrain(_, TerrainTile::Half(..) | TerrainTile::SingleHalf(_))) => {
+            Some(Collider::compound(vec![(
+                Vec2::new(0.0, 0.25),
+                0.0,
+                Collider::cuboid(0.5, 0.25),
+            )]))
+        }
         Feature::GroundBlock(_, _, _)
         | Feature::Tile(_, _)
         | Feature::BigMushroomTop(_, _)
         | Feature::CrateCrossRect(_)
-        | Feature::CrateRandomRect(_) => Some(Collider::cuboid(
+        | Feature::CrateRandomRect(_)
+        | Feature::BonusBlock(_, _)
+        | Feature::Coin(_, _) => Some(Collider::cuboid(
             f.bounds().x.size() as f32 / 2.0,
             f.bounds().y.size() as f32 / 2.0,
         )),
 
+        // only the branch is solid, the trunk is scenery
+        Feature::BroadleafTree { height, .. } => Some(Collider::compound(vec![(
+            Vec2::new(0.0, height as f32 / 2.0 - 1.5),
+            0.0,
+            Collider::cuboid(1.5, 0.5),
+        )])),
+
+        // the spike row along the bottom isn't solid
+        Feature::CaveCeiling { box2, .. } => Some(Collider::compound(vec![(
+            Vec2::new(0.0, 0.5),
+            0.0,
+            Collider::cuboid(box2.x.size() as f32 / 2.0, (box2.y.size() - 1) as f32 / 2.0),
+        )])),
+        // the walls are ground blocks, only the rounded corners of the mouths need colliders
+        Feature::CaveTunnel { box2, .. } => {
+            let (w, h) = (box2.x.size() as f32 / 2.0, box2.y.size() as f32 / 2.0);
+            Some(Collider::compound(vec![
+                (
+                    Vec2::ZERO,
+                    0.0,
+                    Collider::triangle(
+                        Vec2::new(-w, h),
+                        Vec2::new(-w + 1.0, h),
+                        Vec2::new(-w, h - 1.0),
+                    ),
+                ),
+                (
+                    Vec2::ZERO,
+                    0.0,
+                    Collider::triangle(
+                        Vec2::new(w, h),
+                        Vec2::new(w - 1.0, h),
+                        Vec2::new(w, h - 1.0),
+                    ),
+                ),
+            ]))
+        }
+
+        // a roof and two walls, leaving the door column open
+        Feature::Igloo { box2, door } => {
+            let (w, h) = (box2.x.size() as f32, box2.y.size() as f32);
+            let mut shell = vec![(
+                Vec2::new(0.0, h / 2.0 - 0.5),
+                0.0,
+                Collider::cuboid(w / 2.0, 0.5),
+            )];
+            for wall in [0, box2.x.size() as u32 - 1] {
+                if wall != door {
+                    shell.push((
+                        Vec2::new(wall as f32 + 0.5 - w / 2.0, -0.5),
+                        0.0,
+                        Collider::cuboid(0.5, (h - 1.0) / 2.0),
+                    ));
+                }
+            }
+            Some(Collider::compound(shell))
+        }
+
+        // castles are solid above head height, keeps can be walked into under the roof
+        Feature::CastleKeep(b) => Some(Collider::compound(vec![part(
+            b,
+            Box2::from_box1s(b.x, Box1::from_point(b.y.hi_excl - 1)),
+        )])),
+        Feature::CastleTower(b) => Some(Collider::compound(vec![part(
+            b,
+            Box2::from_box1s(b.x, Box1::new(b.y.lo_incl + 2, b.y.hi_excl)),
+        )])),
+        // the bottom two rows are open all along, like the towers', so the gate can be reached
+        Feature::CastleWall { box2: b, .. } => Some(Collider::compound(vec![part(
+            b,
+            Box2::from_box1s(b.x, Box1::new(b.y.lo_incl + 2, b.y.hi_excl)),
+        )])),
+
+        // lollipop heads and candy poles can be stood on
+        Feature::Candy {
+            prop: CandyProp::Lollipop,
+            box2: b,
+        } => Some(Collider::compound(vec![part(
+            b,
+            Box2::from_box1s(b.x, Box1::from_point(b.y.hi_excl - 1)),
+        )])),
+        Feature::Candy {
+            prop: CandyProp::CandyPole,
+            ..
+        } => Some(Collider::cuboid(
+            f.bounds().x.size() as f32 / 2.0,
+            f.bounds().y.size() as f32 / 2.0,
+        )),
+        Feature::Candy { .. } => None,
+
+        Feature::Factory {
+            prop: FactoryProp::Scaffold,
+            box2: b,
+        } => Some(Collider::compound(vec![part(
+            b,
+            Box2::from_box1s(b.x, Box1::from_point(b.y.hi_excl - 2)),
+        )])),
+        Feature::Factory {
+            prop: FactoryProp::MetalBoxStack,
+            ..
+        } => Some(Collider::cuboid(
+            f.bounds().x.size() as f32 / 2.0,
+            f.bounds().y.size() as f32 / 2.0,
+        )),
+        Feature::Factory { .. } => None,
+
+        // the corners under the ends of the ledge are open
+        Feature::SkyIsland { box2: b, .. } => {
+            let top = Box1::from_point(b.y.hi_excl - 1);
+            let mut parts = vec![part(b, Box2::from_box1s(b.x, top))];
+            if b.y.size() > 1 {
+                parts.push(part(
+                    b,
+                    Box2::new(
+                        (b.x.lo_incl + 1, b.y.lo_incl),
+                        (b.x.hi_excl - 1, top.lo_incl),
+                    ),
+                ));
+            }
+            Some(Collider::compound(parts))
+        }
+
         Feature::HillBlock { .. } => None,
         // Feature::HillBlock { terrain, start_x, height, bridge_thickness: None, lr } => Some({
         //     Collider::
@@ -20,10 +172,11 @@ pub fn collider_for(f: Feature) -> Option<Collider> {
         // Feature::HillBlock { terrain, start_x, height, bridge_thickness: Some(t), lr } => Some({
 
         // }),
-        Feature::Igloo { .. }
-        | Feature::SurfaceWater(_)
-        | Feature::SurfaceLava(_)
+        Feature::Clutter(_, _)
+        | Feature::House { .. }
+        | Feature::SurfaceLiquid(_, _)
         | Feature::BigMushroomStem(_, _)
+        | Feature::PineTree { .. }
         | Feature::SlopedGround { .. }
         | Feature::FlatGround(_, _)
         | Feature::Zone(_, _)
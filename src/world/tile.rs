@@ -1,30 +1,23 @@
 use enum_iterator::Sequence;
 use itertools::iproduct;
 use num_derive::FromPrimitive;
+use serde::{Deserialize, Serialize};
 
 use crate::helpers::*;
 
-type Index = (u16, u16);
+use super::{autotile::*, tileset::Tileset};
+
+pub type Index = (u16, u16);
 type Alt = bool;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum Alt3 {
     Alt0,
     Alt1,
     Alt2,
 }
 
-impl From<Alt3> for u16 {
-    fn from(a: Alt3) -> Self {
-        match a {
-            Alt3::Alt0 => 0,
-            Alt3::Alt1 => 1,
-            Alt3::Alt2 => 2,
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum Alt5 {
     Alt0,
     Alt1,
@@ -33,19 +26,9 @@ pub enum Alt5 {
     Alt4,
 }
 
-impl From<Alt5> for u16 {
-    fn from(a: Alt5) -> Self {
-        match a {
-            Alt5::Alt0 => 0,
-            Alt5::Alt1 => 1,
-            Alt5::Alt2 => 2,
-            Alt5::Alt3 => 3,
-            Alt5::Alt4 => 4,
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, FromPrimitive, Serialize, Deserialize,
+)]
 pub enum Terrain {
     Cake,
     Choco,
@@ -60,13 +43,7 @@ pub enum Terrain {
     Industrial,
 }
 
-impl From<Terrain> for Index {
-    fn from(t: Terrain) -> Self {
-        (0, t as u16 * 4)
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum LR {
     L,
     R,
@@ -81,15 +58,6 @@ impl LR {
     }
 }
 
-impl From<LR> for u16 {
-    fn from(lr: LR) -> Self {
-        match lr {
-            LR::L => 0,
-            LR::R => 1,
-        }
-    }
-}
-
 impl From<LR> for i32 {
     fn from(lr: LR) -> Self {
         match lr {
@@ -99,7 +67,7 @@ impl From<LR> for i32 {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum LMR {
     L,
     M,
@@ -116,16 +84,6 @@ impl LMR {
     }
 }
 
-impl From<LMR> for u16 {
-    fn from(lmr: LMR) -> Self {
-        match lmr {
-            LMR::L => 0,
-            LMR::M => 1,
-            LMR::R => 2,
-        }
-    }
-}
-
 impl From<LMR> for i32 {
     fn from(lmr: LMR) -> Self {
         match lmr {
@@ -136,7 +94,7 @@ impl From<LMR> for i32 {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum TB {
     T,
     B,
@@ -151,15 +109,6 @@ impl TB {
     }
 }
 
-impl From<TB> for u16 {
-    fn from(tb: TB) -> Self {
-        match tb {
-            TB::T => 0,
-            TB::B => 1,
-        }
-    }
-}
-
 impl From<TB> for i32 {
     fn from(tb: TB) -> Self {
         match tb {
@@ -169,7 +118,7 @@ impl From<TB> for i32 {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum TMB {
     T,
     M,
@@ -186,16 +135,6 @@ impl TMB {
     }
 }
 
-impl From<TMB> for u16 {
-    fn from(tmb: TMB) -> Self {
-        match tmb {
-            TMB::T => 0,
-            TMB::M => 1,
-            TMB::B => 2,
-        }
-    }
-}
-
 impl From<TMB> for i32 {
     fn from(tmb: TMB) -> Self {
         match tmb {
@@ -206,7 +145,7 @@ impl From<TMB> for i32 {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum LRTB {
     L,
     R,
@@ -245,7 +184,7 @@ pub fn tmb_of(b: Box1<i32>, p: i32) -> TMB {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum TerrainTile {
     RoundLedge(LR),
     OverLedge(LR),
@@ -266,65 +205,25 @@ pub enum TerrainTile {
     Jagged,
 }
 
-impl From<TerrainTile> for Index {
-    fn from(t: TerrainTile) -> Self {
-        match t {
-            TerrainTile::RoundLedge(lr) => (0 + u16::from(lr), 0),
-            TerrainTile::OverLedge(lr) => (2 + u16::from(lr), 0),
-            TerrainTile::SlopeLedge(lr) => (4 + u16::from(lr), 0),
-            TerrainTile::BlockLedge(lr) => (6 + u16::from(lr), 0),
-            TerrainTile::BlockFace(lmr, tmb) => (u16::from(lmr), 1 + u16::from(tmb)),
-            TerrainTile::Slope(lr) => (3 + u16::from(lr), 1),
-            TerrainTile::SlopeInt(lr) => (3 + u16::from(lr), 2),
-            TerrainTile::Cap(lr) => (6 + u16::from(lr), 3),
-            TerrainTile::FaceInt(lr, tb) => (5 + u16::from(lr), 2 - u16::from(tb)),
-            TerrainTile::RockSlope(lr, tb) => (9 + u16::from(lr), 2 + u16::from(tb)),
-            TerrainTile::Block => (8, 2),
-            TerrainTile::BareBlock => (3, 3),
-            TerrainTile::Single => (4, 3),
-            TerrainTile::SingleBare => (5, 3),
-            TerrainTile::SingleHalf(a) => (8, u16::from(a)),
-            TerrainTile::Half(a, lmr) => (9 + u16::from(lmr), u16::from(a)),
-            TerrainTile::Jagged => (8, 3),
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, FromPrimitive, Serialize, Deserialize,
+)]
 pub enum RockType {
     Sandstone,
     Slate,
     Stone,
 }
 
-impl From<RockType> for Index {
-    fn from(rt: RockType) -> Self {
-        match rt {
-            RockType::Sandstone => (12, 0),
-            RockType::Slate => (12, 3),
-            RockType::Stone => (12, 6),
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, FromPrimitive, Serialize, Deserialize,
+)]
 pub enum Roof {
     Brick,
     Slate,
     Straw,
 }
 
-impl From<Roof> for Index {
-    fn from(r: Roof) -> Self {
-        match r {
-            Roof::Brick => (16, 0),
-            Roof::Slate => (16, 3),
-            Roof::Straw => (16, 6),
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum CaveTile {
     Slope(LR, TB),
     Spike(TB),
@@ -333,25 +232,22 @@ pub enum CaveTile {
     SmallRock,
 }
 
-impl From<CaveTile> for Index {
-    fn from(c: CaveTile) -> Self {
-        match c {
-            CaveTile::Slope(lr, tb) => (19 + u16::from(lr), u16::from(tb)),
-            CaveTile::Spike(tb) => (21, u16::from(tb)),
-            CaveTile::Jagged(tb) => (22, u16::from(tb)),
-            CaveTile::BigRock => (23, 0),
-            CaveTile::SmallRock => (23, 1),
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum Cave {
     Dirt,
     Stone,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+impl From<Cave> for Terrain {
+    fn from(c: Cave) -> Self {
+        match c {
+            Cave::Dirt => Terrain::Dirt,
+            Cave::Stone => Terrain::Stone,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum Color4 {
     Y,
     R,
@@ -359,38 +255,16 @@ pub enum Color4 {
     B,
 }
 
-impl From<Color4> for u16 {
-    fn from(c: Color4) -> Self {
-        match c {
-            Color4::Y => 0,
-            Color4::R => 1,
-            Color4::G => 2,
-            Color4::B => 3,
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum TreeTile {
     Top { snow: bool },
     PineBranch { snow: bool, lr: LR, double: bool },
     PineTrunk,
 }
 
-impl From<TreeTile> for Index {
-    fn from(tt: TreeTile) -> Self {
-        match tt {
-            TreeTile::Top { snow } => (17, 16 + u16::from(snow)),
-            TreeTile::PineBranch { snow, lr, double } => (
-                20 + u16::from(lr) - 2 * u16::from(snow),
-                16 + u16::from(double),
-            ),
-            TreeTile::PineTrunk => (22, 17),
-        }
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Sequence, FromPrimitive)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, FromPrimitive, Serialize, Deserialize,
+)]
 pub enum MushroomStyle {
     Caramel,
     Brown,
@@ -398,7 +272,7 @@ pub enum MushroomStyle {
     White,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Sequence, Serialize, Deserialize)]
 pub enum Tile {
     Air,
 
@@ -772,383 +646,11 @@ pub enum Tile {
     CastleRoofLowCont(Alt, LR),
 }
 
-impl From<Tile> for Index {
-    fn from(t: Tile) -> Self {
-        match t {
-            Tile::Air => (12, 32),
-
-            Tile::Terrain(t, tt) => {
-                let (x1, y1) = Index::from(t);
-                let (x2, y2) = Index::from(tt);
-                (x1 + x2, y1 + y2)
-            }
-            Tile::MetalTri => (7, 9),
-            Tile::MetalYellowSquare => (7, 10),
-            Tile::Building(rt, lmb, tmb) => (
-                Index::from(rt).0 + u16::from(lmb),
-                Index::from(rt).1 + u16::from(tmb),
-            ),
-            Tile::BuildingInt(rt, a) => (Index::from(rt).0 + 3, Index::from(rt).1 + u16::from(a)),
-            Tile::Roof(r, lmr, tb) => (
-                Index::from(r).0 + u16::from(lmr),
-                Index::from(r).1 + u16::from(tb),
-            ),
-            Tile::Cave(c, ct) => (
-                Index::from(ct).0,
-                Index::from(ct).1
-                    + match c {
-                        Cave::Dirt => 0,
-                        Cave::Stone => 2,
-                    },
-            ),
-            Tile::BigSnowball => (23, 4),
-            Tile::SmallSnowball => (22, 5),
-            Tile::GroundSnowball => (21, 5),
-            Tile::SnowPile => (22, 4),
-            Tile::GreenArrow(lrtb) => match lrtb {
-                LRTB::L => (22, 6),
-                LRTB::R => (24, 6),
-                LRTB::T => (23, 5),
-                LRTB::B => (23, 7),
-            },
-            Tile::MetalFence(lmr) => (24 + u16::from(lmr), 0),
-            Tile::MetalUpper => (24, 1),
-            Tile::MetalUpperWire { long } => (25 + u16::from(long), 1),
-            Tile::GirderSmall { bolts } => (24 + u16::from(bolts), 2),
-            Tile::Girder { bolts } => (26 + u16::from(bolts), 3),
-            Tile::GirderHoles { bolts } => (24 + u16::from(bolts), 2),
-            Tile::Railing(a) => (26 + u16::from(a), 2),
-            Tile::Beam(lr) => (24 + u16::from(lr), 4),
-            Tile::Strut(lr, tb) => (26 + u16::from(lr), 4 + u16::from(tb)),
-            Tile::Hook(tb) => (24 + u16::from(tb), 5),
-            Tile::Gummy(c) => (12, 12 - u16::from(c)),
-            Tile::Cherry => (13, 9),
-            Tile::Heart => (17, 9),
-            Tile::Cone => (17, 10),
-            Tile::CookieBlackWhite => (13, 10),
-            Tile::CookieBeigeBrown => (14, 10),
-            Tile::CookieBeigePink => (14, 9),
-            Tile::IcecreamBeige => (15, 9),
-            Tile::IcecreamWhite => (15, 10),
-            Tile::IcecreamPink => (16, 9),
-            Tile::IcecreamBrown => (16, 10),
-            Tile::WaferWhite => (22, 10),
-            Tile::WaferPink => (23, 9),
-            Tile::WaferBrown => (23, 10),
-            Tile::GummyWormHookGreenYellow => (18, 9),
-            Tile::GummyWormLoopGreenYellow => (19, 9),
-            Tile::GummyWormTailGreenYellow => (18, 10),
-            Tile::GummyWormHookRedWhite => (20, 9),
-            Tile::GummyWormLoopRedWhite => (21, 9),
-            Tile::GummyWormTailRedWhite => (20, 10),
-            Tile::CandyPoleBrown(tb) => (20, 11 + u16::from(tb)),
-            Tile::CandyPoleGreen(tb) => (21, 11 + u16::from(tb)),
-            Tile::CandyPolePink(tb) => (22, 11 + u16::from(tb)),
-            Tile::CandyPoleRed(tb) => (23, 11 + u16::from(tb)),
-            Tile::LittleCandyCaneRed => (14, 12),
-            Tile::CandyCaneBaseRed => (13, 12),
-            Tile::CandyCaneTopRed(a) => (13 + u16::from(a), 11),
-            Tile::LittleCandyCaneGreen => (16, 12),
-            Tile::CandyCaneBaseGreen => (15, 12),
-            Tile::CandyCaneTopGreen(a) => (15 + u16::from(a), 11),
-            Tile::LittleCandyCanePink => (18, 12),
-            Tile::CandyCaneBasePink => (17, 12),
-            Tile::CandyCaneTopPink(a) => (17 + u16::from(a), 11),
-            Tile::LollipopStickWhite => (12, 13),
-            Tile::LollipopStickBeige => (13, 13),
-            Tile::LollipopStickBrown => (14, 13),
-            Tile::LollipopBaseBeige => (15, 13),
-            Tile::LollipopBasePink => (16, 13),
-            Tile::LollipopGreen => (17, 13),
-            Tile::LollipopRed => (18, 13),
-            Tile::LollipopYellow => (19, 13),
-            Tile::LollipopGreenSwirl(a) => (20 + u16::from(a), 13),
-            Tile::LollipopRedSwirl(a) => (22 + u16::from(a), 13),
-            Tile::MetalBoxWire(a) => (24 + u16::from(a), 9),
-            Tile::MetalBoxCross(a) => (24 + u16::from(a), 10),
-            Tile::MetalBoxSlash(a) => (24 + u16::from(a), 11),
-            Tile::MetalBoxBlank(a) => (24 + u16::from(a), 12),
-            Tile::SlimeSingle(tb) => (12, 14 + u16::from(tb)),
-            Tile::Slime(lmr, tb) => (13 + u16::from(lmr), 14 + u16::from(tb)),
-            Tile::SlimeBubble(tb) => (16, 14 + u16::from(tb)),
-            Tile::PineTree(tt) => Index::from(tt),
-            Tile::TrunkStraight => (17, 14),
-            Tile::TrunkFork(lr) => (18 + u16::from(lr), 14),
-            Tile::TrunkDeadFork => (20, 14),
-            Tile::TrunkKnotBranch(lr) => (21 + 2 * u16::from(lr), 14),
-            Tile::TrunkBranch(lmr) => (21 + u16::from(lmr), 15),
-            Tile::TrunkBase => (17, 15),
-            Tile::TrunkBaseNarrow => (18, 15),
-            Tile::TrunkBaseSnow => (19, 15),
-            Tile::TrunkBaseSnowPile => (20, 15),
-            Tile::LavaWave => (12, 16),
-            Tile::LavaSingle => (13, 16),
-            Tile::Lava => (14, 16),
-            Tile::WaterWave => (12, 17),
-            Tile::WaterSingle => (13, 17),
-            Tile::Water => (14, 17),
-            Tile::IceWaterWave => (12, 18),
-            Tile::IceWaterSingle => (13, 18),
-            Tile::IceWater => (14, 18),
-            Tile::SparklingWaterWave => (12, 19),
-            Tile::SparklingWaterSingle => (13, 19),
-            Tile::SparklingWater => (14, 19),
-            Tile::DeepWater(a) => (15, 18 + u16::from(a)),
-            Tile::IceBlock => (15, 16),
-            Tile::SparkleIceBlock => (15, 17),
-            Tile::IceHalf => (16, 16),
-            Tile::SparkleIceHalf => (16, 17),
-            Tile::MushroomBlock(MushroomStyle::Caramel, a, lmr) => {
-                (12 + u16::from(lmr), 20 + u16::from(a))
-            }
-            Tile::MushroomStemBlock(MushroomStyle::Caramel, a) => (15, 20 + u16::from(a)),
-            Tile::MushroomBlock(MushroomStyle::Brown, a, lmr) => {
-                (12 + u16::from(lmr), 22 + u16::from(a))
-            }
-            Tile::MushroomStemBlock(MushroomStyle::Brown, a) => (15, 22 + u16::from(a)),
-            Tile::MushroomBlock(MushroomStyle::Red, a, lmr) => {
-                (12 + u16::from(lmr), 24 + u16::from(a))
-            }
-            Tile::MushroomStemBlock(MushroomStyle::Red, a) => (15, 24 + u16::from(a)),
-            Tile::MushroomBlock(MushroomStyle::White, a, lmr) => {
-                (12 + u16::from(lmr), 26 + u16::from(a))
-            }
-            Tile::MushroomStemBlock(MushroomStyle::White, a) => (15, 26 + u16::from(a)),
-            Tile::MushroomStemTop(a) => (16, 20 + u16::from(a)),
-            Tile::MushroomStemLeaf => (16, 22),
-            Tile::MushroomStemRing(a) => (16, 23 + u16::from(a)),
-            Tile::MushroomStem => (16, 25),
-            Tile::MushroomStemBase(a) => (16, 26 + u16::from(a)),
-            Tile::MushroomWhite(a) => (17 + u16::from(a), 25),
-            Tile::MushroomRed(a) => (17 + u16::from(a), 26),
-            Tile::MushroomBrown(a) => (17 + u16::from(a), 27),
-            Tile::BrickBlock => (22, 22),
-            Tile::StoneBlock => (23, 22),
-            Tile::CrateBlank => (19, 22),
-            Tile::CrateSlash => (20, 22),
-            Tile::CrateCross => (21, 22),
-            Tile::CrateSquareBang => (19, 19),
-            Tile::CrateTriangleBang => (20, 19),
-            Tile::BangBox { empty, alt } => (19 + u16::from(empty) + 2 * u16::from(alt), 20),
-            Tile::CoinBox { empty, alt } => (19 + u16::from(empty) + 2 * u16::from(alt), 21),
-            Tile::TriangleBangBoxAlt { empty } => (21 + u16::from(empty), 19),
-            Tile::CrenellationOverhang(lr, a) => (19 + u16::from(lr), 24 + u16::from(a)),
-            Tile::Crenellation(a) => (21, 24 + u16::from(a)),
-            Tile::CrenellationBroken(a) => (22, 24 + u16::from(a)),
-            Tile::CrenellationHalf => (23, 24),
-            Tile::CrenellationHalfBroken => (24, 24),
-            Tile::CrenellationHalfOpen => (25, 24),
-            Tile::SnowSlope(lr) => (12 + u16::from(lr), 28),
-            Tile::SnowPileBig => (14, 28),
-            Tile::SnowPileSmall => (15, 28),
-            Tile::SnowPileLow(lmr) => (16 + u16::from(lmr), 28),
-            Tile::SnowDrift => (19, 28),
-            Tile::Icicle(tb, alt3) => (12 + u16::from(alt3), 29 + u16::from(tb)),
-            Tile::Gate(lmr, tb, a) => (20 + u16::from(lmr) + 3 * u16::from(a), 27 + u16::from(tb)),
-            Tile::GateDoor(lmr, tb, a) => {
-                (20 + u16::from(lmr) + 3 * u16::from(a), 29 + u16::from(tb))
-            }
-            Tile::GateSpikes(lmr, tb) => (20 + u16::from(lmr), 31 + u16::from(tb)),
-            Tile::GateBars(lmr, tb) => (23 + u16::from(lmr), 31 + u16::from(tb)),
-            Tile::GrassTuft => (14, 32),
-            Tile::Cactus => (15, 32),
-            Tile::Sapling => (16, 32),
-            Tile::SaplingTall => (17, 32),
-            Tile::IceTuft => (18, 32),
-            Tile::IceCrystal => (19, 32),
-            Tile::PurpleCrystal => (14, 33),
-            Tile::Moss => (15, 33),
-            Tile::IceMoss => (16, 33),
-            Tile::Rock => (12, 33),
-            Tile::IceRock(a) => (12 + u16::from(a), 34),
-            Tile::MossRock(a) => (12 + u16::from(a), 35),
-            Tile::FrozenShrub => (14, 35),
-            Tile::StoneSpike => (15, 35),
-            Tile::StoneSpike2(tb) => (16, 34 + u16::from(tb)),
-            Tile::IglooTop(lmr) => (17 + u16::from(lmr), 34),
-            Tile::IglooInterior(a) => (17 + u16::from(a), 35),
-            Tile::IglooDoor => (19, 35),
-            Tile::Laser => (22, 33),
-            Tile::Laser2 => (22, 34),
-            Tile::Fireball => (22, 35),
-            Tile::Brick(a) => (23, 33 + u16::from(a)),
-            Tile::StoneBrick(a) => (24, 33 + u16::from(a)),
-            Tile::Star => (23, 35),
-            Tile::TranslucentWindow(c) => (0, 44 + u16::from(c)),
-            Tile::TranslucentWindowBox(c) => (1, 44 + u16::from(c)),
-            Tile::BarrelSide(c) => (2, 44 + u16::from(c)),
-            Tile::Barrel(c) => (3, 44 + u16::from(c)),
-            Tile::LightStick(c) => (4, 44 + u16::from(c)),
-            Tile::Light(c) => (5, 44 + u16::from(c)),
-            Tile::Key(c) => (6, 44 + u16::from(c)),
-            Tile::Crystal(c) => (7, 44 + u16::from(c)),
-            Tile::FlagFrame1(c) => (8, 44 + u16::from(c)),
-            Tile::FlagFrame2(c) => (9, 44 + u16::from(c)),
-            Tile::FallenFlag(c) => (10, 44 + u16::from(c)),
-            Tile::Button { pressed, color } => (11 + u16::from(pressed), 44 + u16::from(color)),
-            Tile::Lock(c) => (13, 44 + u16::from(c)),
-            Tile::LaserLever(lr, c) => (14 + u16::from(lr), 44 + u16::from(c)),
-            Tile::LaserSpark(c) => (16, 44 + u16::from(c)),
-            Tile::LaserBeamH(c) => (17, 44 + u16::from(c)),
-            Tile::LaserBeamV(c) => (18, 44 + u16::from(c)),
-            Tile::TranslucentBarrel(c) => (19, 44 + u16::from(c)),
-            Tile::Zapper(lrtb, a) => (
-                20 + u16::from(a),
-                match lrtb {
-                    LRTB::L => 46,
-                    LRTB::R => 47,
-                    LRTB::T => 44,
-                    LRTB::B => 45,
-                },
-            ),
-            Tile::Lever(lmr) => (22 + u16::from(lmr), 45),
-            Tile::Chain => (23, 46),
-            Tile::Weight => (22, 47),
-            Tile::WeightChain => (23, 47),
-            Tile::Spring => (22, 44),
-            Tile::SpringUp => (23, 44),
-            Tile::Grinder(a) => (23 + u16::from(a), 42),
-            Tile::HalfGrinder(a) => (23 + u16::from(a), 43),
-            Tile::CoinBronze => (22, 41),
-            Tile::CoinSilver => (24, 41),
-            Tile::CoinGold => (23, 41),
-            Tile::SwordBronze => (22, 40),
-            Tile::SwordSilver => (24, 40),
-            Tile::SwordGold => (23, 40),
-            Tile::ShieldBronze => (22, 39),
-            Tile::ShieldSilver => (24, 39),
-            Tile::ShieldGold => (23, 39),
-            Tile::PurpleGun => (22, 37),
-            Tile::PurpleGunFiring => (23, 37),
-            Tile::SilverGun => (22, 38),
-            Tile::SilverGunFiring => (23, 38),
-            Tile::Bomb => (24, 38),
-            Tile::BombFlash => (24, 37),
-            Tile::Chimney(a) => (12 + u16::from(a), 37),
-            Tile::SignBed { hanging } => (12, 38 + u16::from(hanging)),
-            Tile::SignCoin { hanging } => (13, 38 + u16::from(hanging)),
-            Tile::SignMug { hanging } => (14, 38 + u16::from(hanging)),
-            Tile::TorchHolder => (15, 37),
-            Tile::Torch(lr) => (15, 38 + u16::from(lr)),
-            Tile::Umbrella { open } => (16 + u16::from(open), 38),
-            Tile::Clock => (16, 37),
-            Tile::WeatherVane => (17, 37),
-            Tile::Shade(a) => (12 + u16::from(a), 40),
-            Tile::RopeVHook => (12, 41),
-            Tile::RopeH => (13, 41),
-            Tile::RopeV => (14, 41),
-            Tile::Fence(a) => (12 + u16::from(a), 42),
-            Tile::FenceBroken => (14, 42),
-            Tile::FenceLow => (15, 42),
-            Tile::FenceOpen => (16, 42),
-            Tile::FenceLowerHalf => (17, 42),
-            Tile::FenceLower => (18, 42),
-            Tile::Bridge => (19, 42),
-            Tile::BridgeLog => (20, 42),
-            Tile::SignExit => (12, 43),
-            Tile::SignArrow(lr) => (13 + u16::from(lr), 43),
-            Tile::SignBlank => (15, 43),
-            Tile::DoorwayGrey(tb) => (17, 40 + u16::from(tb)),
-            Tile::DoorwayBeige(tb) => (21, 40 + u16::from(tb)),
-            Tile::DoorLockedGrey(tb) => (16, 40 + u16::from(tb)),
-            Tile::DoorLockedBeige(tb) => (20, 40 + u16::from(tb)),
-            Tile::DoorInsetBeige(tb) => (19, 40 + u16::from(tb)),
-            Tile::DoorTopWindow => (18, 40),
-            Tile::DoorBeige => (18, 41),
-            Tile::WindowOpenHalf => (16, 39),
-            Tile::WindowStainedHalf => (17, 39),
-            Tile::WindowOpen => (18, 39),
-            Tile::Window(a) => (19 + u16::from(a), 39),
-            Tile::HighWindowOpen(tmb) => (18, 36 + u16::from(tmb)),
-            Tile::HighWindow(tmb, a) => (19 + u16::from(a), 36 + u16::from(tmb)),
-            Tile::CastleWindowOpenSmall(a) => (0 + 2 * u16::from(a), 48),
-            Tile::CastleWindowShutSmall(a) => (1 + 2 * u16::from(a), 48),
-            Tile::CastleWindowOpen(a) => (0 + 2 * u16::from(a), 49),
-            Tile::CastleWindowShut(a) => (1 + 2 * u16::from(a), 49),
-            Tile::CastleWindowOpenHigh(a, tmb) => (0 + 2 * u16::from(a), 51 + u16::from(tmb)),
-            Tile::CastleWindowShutHigh(a, tmb) => (1 + 2 * u16::from(a), 51 + u16::from(tmb)),
-            Tile::CastleWindowOpenSlit(a, tmb) => (0 + 2 * u16::from(a), 55 + u16::from(tmb)),
-            Tile::CastleWindowShutSlit(a, tmb) => (1 + 2 * u16::from(a), 55 + u16::from(tmb)),
-            Tile::CastleWindowOpenHighAlt(a) => (0 + 2 * u16::from(a), 50),
-            Tile::CastleWindowShutHighAlt(a) => (1 + 2 * u16::from(a), 50),
-            Tile::CastleWindowOpenSlitAlt(a) => (0 + 2 * u16::from(a), 54),
-            Tile::CastleWindowShutSlitAlt(a) => (1 + 2 * u16::from(a), 54),
-            Tile::BannerSmallRed(tmb, a) => (4 + 2 * u16::from(a), 48 + u16::from(tmb)),
-            Tile::BannerSmallGreen(tmb, a) => (5 + 2 * u16::from(a), 48 + u16::from(tmb)),
-            Tile::TapestryMidRed(a) => (4, 51 + u16::from(a)),
-            Tile::TapestryMidGreen(a) => (5, 51 + u16::from(a)),
-            Tile::TapestryBottomRed(a) => (4, 56 + u16::from(a)),
-            Tile::TapestryBottomGreen(a) => (5, 56 + u16::from(a)),
-            Tile::BannerRed(a) => (6, 51 + u16::from(a)),
-            Tile::BannerGreen(a) => (7, 51 + u16::from(a)),
-            Tile::FlagWoodRedFrame1(lr) => (8 + 2 * u16::from(lr), 48),
-            Tile::FlagWoodRedFrame2(lr) => (9 + 2 * u16::from(lr), 48),
-            Tile::FlagWoodGreenFrame1(lr) => (8 + 2 * u16::from(lr), 51),
-            Tile::FlagWoodGreenFrame2(lr) => (9 + 2 * u16::from(lr), 51),
-            Tile::FlagWoodLongRedFrame1(lr) => (8 + u16::from(lr), 49 + u16::from(lr)),
-            Tile::FlagWoodLongRedFrame2(lr) => (10 + u16::from(lr), 49 + u16::from(lr)),
-            Tile::FlagWoodLongGreenFrame1(lr) => (8 + u16::from(lr), 52 + u16::from(lr)),
-            Tile::FlagWoodLongGreenFrame2(lr) => (10 + u16::from(lr), 49 + u16::from(lr)),
-            Tile::FlagWoodTipRedFrame1(lr) => (9 - u16::from(lr), 49 + u16::from(lr)),
-            Tile::FlagWoodTipRedFrame2(lr) => (11 - u16::from(lr), 49 + u16::from(lr)),
-            Tile::FlagWoodTipGreenFrame1(lr) => (9 - u16::from(lr), 52 + u16::from(lr)),
-            Tile::FlagWoodTipGreenFrame2(lr) => (11 - u16::from(lr), 52 + u16::from(lr)),
-            Tile::FlagpoleTop(lmr) => match lmr {
-                LMR::L => (8, 54),
-                LMR::M => (10, 54),
-                LMR::R => (9, 54),
-            },
-            Tile::Flagpole(lmr) => match lmr {
-                LMR::L => (8, 55),
-                LMR::M => (10, 55),
-                LMR::R => (9, 55),
-            },
-            Tile::FlagpoleAlt(a) => (10 + u16::from(a), 56),
-            Tile::FallenFlagRed(lr) => (8 + u16::from(lr), 56),
-            Tile::FallenFlagGreen(lr) => (8 + u16::from(lr), 57),
-            Tile::FlagBase(a) => (10 + u16::from(a), 57),
-            Tile::TorchWood(lr) => (10 + u16::from(lr), 58),
-            Tile::Console { lmr, on, knobs } => (
-                match lmr {
-                    Some(lmr) => 12 + u16::from(lmr),
-                    None if knobs => 16,
-                    None => 15,
-                },
-                48 + u16::from(on),
-            ),
-            Tile::ConsoleButtons(lmr) => (12 + u16::from(lmr), 50),
-            Tile::ConsoleButtonsSmall(lmr) => (12 + u16::from(lmr), 51),
-            Tile::Shelves(tb) => (18, 48 + u16::from(tb)),
-            Tile::ShelvesNarrow(tb) => (19, 48 + u16::from(tb)),
-            Tile::Pillar(tmb) => (20, 48 + u16::from(tmb)),
-            Tile::BangSticker => (17, 50),
-            Tile::DiamondSticker => (18, 50),
-            Tile::CoinSticker => (19, 50),
-            Tile::TapeYellow(a) => (16 + u16::from(a), 51),
-            Tile::TapeRed(a) => (16 + u16::from(a), 52),
-            Tile::CrenellationsBrickTop(lmr) => (12 + u16::from(lmr), 52),
-            Tile::CrenellationsBrick(lmr) => (12 + u16::from(lmr), 53),
-            Tile::TapestryHolder(lr) => (12 + 2 * u16::from(lr), 56),
-            Tile::TapestryTopRed => (13, 56),
-            Tile::TapestryTopGreen => (15, 56),
-            Tile::CastleStoneWall(a) => (18 + u16::from(a), 54),
-            Tile::Castle(a, lmr, tb) => {
-                (21 + 3 * u16::from(a) + u16::from(lmr), 53 + u16::from(tb))
-            }
-            Tile::CastleBeamSlash(a, lmr) => (21 + 3 * u16::from(a) + u16::from(lmr), 52),
-            Tile::CastleBeamCross(a, lmr) => (21 + 3 * u16::from(a) + u16::from(lmr), 51),
-            Tile::CastleBeamSlats(a, tmb) => (23 + 3 * u16::from(a), 48 + u16::from(tmb)),
-            Tile::CastleSlash(a, tmb) => (22 + 3 * u16::from(a), 48 + u16::from(tmb)),
-            Tile::CastleBeamSlashV(a, tb) => (21 + 3 * u16::from(a), 48 + 2 * u16::from(tb)),
-            Tile::CastleSlat(a) => (21 + 3 * u16::from(a), 49),
-            Tile::CastleRoof(a2, a) => (12 + u16::from(a), 57 + u16::from(a2)),
-            Tile::CastleRoofPeak(a, lr) => (14 + u16::from(lr), 57 + u16::from(a)),
-            Tile::CastleRoofSlope(a, lr) => (16 + u16::from(lr), 57 + u16::from(a)),
-            Tile::CastleRoofBend(a, lr) => (18 + u16::from(lr), 57 + u16::from(a)),
-            Tile::CastleRoofPeakBase(a, lr) => (20 + u16::from(lr), 57 + u16::from(a)),
-            Tile::CastleRoofLow(a, lr) => (22 + u16::from(lr), 57 + u16::from(a)),
-            Tile::CastleRoofLowCont(a, lr) => (24 + u16::from(lr), 57 + u16::from(a)),
-        }
+impl Tile {
+    // The tile drawn from cell `at` of the sheet, if any is. Tiles listed as the same as another
+    // come back as that one
+    pub fn from_index(tileset: &Tileset, at: Index) -> Option<Tile> {
+        tileset.tile_at(at)
     }
 }
 
@@ -1165,9 +667,84 @@ pub enum TilingTile {
     Ground(GroundCover, Terrain),
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
-enum TilingCorner {
+// where a tile goes in its cell's stack, higher ones being drawn over lower ones
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Z {
+    Behind,
+    Base,
+    Over,
+}
+
+// The tiles one layer of a cell is drawn with, lowest first. Tiles with the same `Z` keep the
+// order they were put in, so the last of them is on top
+#[derive(Clone, Default, Debug)]
+pub struct TileStack(Vec<(Z, TilingTile)>);
+
+impl TileStack {
+    pub fn push(&mut self, z: Z, t: TilingTile) {
+        let at = self.0.partition_point(|&(z1, _)| z1 <= z);
+        self.0.insert(at, (z, t));
+    }
+
+    pub fn top(&self) -> TilingTile {
+        self.0
+            .last()
+            .map_or(TilingTile::Exactly(Tile::Air), |&(_, t)| t)
+    }
+
+    // The tiles that are drawn as they are, lowest first. Ground needs tiling before it can be
+    // drawn, so it's left out
+    pub fn tiles(&self) -> impl Iterator<Item = Tile> + '_ {
+        Self::drawn(&self.0)
+    }
+
+    // Which tile the cell is tiled as: its top, bar ground under something that doesn't join up
+    // with the ground around it, like a crate standing in it, which keeps the ground tiled
+    fn tiled_at(&self) -> Option<usize> {
+        let top = self.0.len().checked_sub(1)?;
+        match self.0[top].1 {
+            t @ TilingTile::Exactly(_) if t.info().is_none() => Some(
+                (0..top)
+                    .rev()
+                    .find(|&k| matches!(self.0[k].1, TilingTile::Ground(..)))
+                    .unwrap_or(top),
+            ),
+            _ => Some(top),
+        }
+    }
+
+    pub fn tiled(&self) -> TilingTile {
+        self.tiled_at()
+            .map_or(TilingTile::Exactly(Tile::Air), |k| self.0[k].1)
+    }
+
+    // the tiles drawn once what `tiled` gave has been tiled as `tile`
+    pub fn tiles_with(&self, tile: Tile) -> impl Iterator<Item = Tile> + '_ {
+        let at = self.tiled_at();
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(move |(k, &(_, t))| match t {
+                _ if Some(k) == at => Some(tile),
+                TilingTile::Ground(..) => None,
+                TilingTile::Exactly(t) => Some(t),
+            })
+            .filter(|&t| t != Tile::Air)
+    }
+
+    fn drawn(stack: &[(Z, TilingTile)]) -> impl Iterator<Item = Tile> + '_ {
+        stack.iter().filter_map(|&(_, t)| match t {
+            TilingTile::Exactly(Tile::Air) | TilingTile::Ground(..) => None,
+            TilingTile::Exactly(t) => Some(t),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Deserialize)]
+pub enum TilingCorner {
+    // spelled out so it isn't read as an option left empty
     #[default]
+    #[serde(rename = "Square")]
     None,
     Inner,
     Slope,
@@ -1369,7 +946,37 @@ impl TilingTile {
                         info.left = TilingSide::Interior;
                         info.right = TilingSide::Interior;
                     }
-                    _ => (),
+                    // the ends of ground one tile high, whose top edge runs on into the next tile
+                    TerrainTile::RoundLedge(lr)
+                    | TerrainTile::OverLedge(lr)
+                    | TerrainTile::SlopeLedge(lr)
+                    | TerrainTile::BlockLedge(lr) => match lr {
+                        LR::L => {
+                            info.right = TilingSide::Interior;
+                            info.rt = TilingCorner::Inner;
+                        }
+                        LR::R => {
+                            info.left = TilingSide::Interior;
+                            info.lt = TilingCorner::Inner;
+                        }
+                    },
+                    // platforms half a tile high, edged along the top and the bottom
+                    TerrainTile::Half(_, lmr) => {
+                        if lmr != LMR::L {
+                            info.left = TilingSide::Interior;
+                            info.lt = TilingCorner::Inner;
+                            info.lb = TilingCorner::Inner;
+                        }
+                        if lmr != LMR::R {
+                            info.right = TilingSide::Interior;
+                            info.rt = TilingCorner::Inner;
+                            info.rb = TilingCorner::Inner;
+                        }
+                    }
+                    // these stand alone, edged all round
+                    TerrainTile::Block | TerrainTile::BareBlock | TerrainTile::SingleHalf(_) => (),
+                    // caps are drawn over the tiling after it, and rock slopes don't join up
+                    TerrainTile::Cap(_) | TerrainTile::RockSlope(..) => (),
                 }
                 info
             }),
@@ -1393,6 +1000,48 @@ impl TilingTile {
             }),
         }
     }
+
+    // whether `right` can sit just right of this tile, with the sides and the corners along
+    // them agreeing. Non-terrain tiles count as open on every side
+    pub fn fits_left_of(self, right: TilingTile) -> bool {
+        let a = self.info();
+        let b = right.info();
+        let side = |ti: Option<TileTilingInfo>, f: fn(&TileTilingInfo) -> TilingSide| {
+            ti.as_ref().map_or(TilingSide::Exterior, f)
+        };
+        let corner = |ti: Option<TileTilingInfo>, f: fn(&TileTilingInfo) -> TilingCorner| {
+            ti.as_ref().map_or(TilingCorner::None, f)
+        };
+        side(a, |ti| ti.right) == side(b, |ti| ti.left)
+            && corner(a, |ti| ti.rt) == corner(b, |ti| ti.lt)
+            && corner(a, |ti| ti.rb) == corner(b, |ti| ti.lb)
+            && (side(a, |ti| ti.right) == TilingSide::Exterior
+                || a.map(|ti| ti.terrain) == b.map(|ti| ti.terrain))
+    }
+
+    // whether `below` can sit just under this tile
+    pub fn fits_above(self, below: TilingTile) -> bool {
+        let a = self.info();
+        let b = below.info();
+        let side = |ti: Option<TileTilingInfo>, f: fn(&TileTilingInfo) -> TilingSide| {
+            ti.as_ref().map_or(TilingSide::Exterior, f)
+        };
+        let corner = |ti: Option<TileTilingInfo>, f: fn(&TileTilingInfo) -> TilingCorner| {
+            ti.as_ref().map_or(TilingCorner::None, f)
+        };
+        side(a, |ti| ti.bottom) == side(b, |ti| ti.top)
+            && corner(a, |ti| ti.bl) == corner(b, |ti| ti.tl)
+            && corner(a, |ti| ti.br) == corner(b, |ti| ti.tr)
+            && (side(a, |ti| ti.bottom) == TilingSide::Exterior
+                || a.map(|ti| ti.terrain) == b.map(|ti| ti.terrain))
+    }
+
+    // whether the tile is open to the air above it and solid below
+    pub fn is_top(self) -> bool {
+        self.info().map_or(false, |ti| {
+            ti.top == TilingSide::Exterior && ti.bottom == TilingSide::Interior
+        })
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -1401,8 +1050,12 @@ pub struct CapInfo {
     pub right_cap: Option<Terrain>,
 }
 
-// Takes in an array extended by one tile on each side to compute the correct output
-pub fn compute_tiling(array: ndarray::Array2<TilingTile>) -> ndarray::Array2<(Tile, CapInfo)> {
+// Takes in an array extended by one tile on each side to compute the correct output, picking the
+// tiles for ground by `rules`
+pub fn compute_tiling(
+    array: ndarray::Array2<TilingTile>,
+    rules: &TilingRules,
+) -> ndarray::Array2<(Tile, CapInfo)> {
     let sx = array.shape()[0];
     let sy = array.shape()[1];
     let mut with_sides = array.map(|t| (*t, t.info()));
@@ -1419,30 +1072,49 @@ pub fn compute_tiling(array: ndarray::Array2<TilingTile>) -> ndarray::Array2<(Ti
                     .and_then(|ti| (ti.bottom == TilingSide::Interior).then_some(ti.terrain));
                 let bottom = info[[i + 1, j]]
                     .and_then(|ti| (ti.top == TilingSide::Interior).then_some(ti.terrain));
+                let below = |x: usize| {
+                    info[[x, j]].map_or(true, |ti| {
+                        ti.top != TilingSide::Interior || ti.terrain != terrain
+                    })
+                };
+
+                // ledges, half tiles and lone columns go by the shape of the ground, whatever
+                // edges its cover would give it
+                let shape = Shape {
+                    left: left != Some(terrain),
+                    right: right != Some(terrain),
+                    top: top != Some(terrain),
+                    bottom: bottom != Some(terrain),
+                    below_left: below(i),
+                    below_right: below(i + 2),
+                    covered: gc != GroundCover::Bare,
+                };
+                if let Some(tt) = rules.for_terrain(terrain).shape_tile(shape) {
+                    let t = TilingTile::Exactly(Tile::Terrain(terrain, tt));
+                    with_sides[[i + 1, j + 1]] = (t, t.info());
+                    continue;
+                }
 
-                let left =
-                    left != Some(terrain) && (gc == GroundCover::FullyCovered || left.is_some());
-                let right =
-                    right != Some(terrain) && (gc == GroundCover::FullyCovered || right.is_some());
                 let top = top != Some(terrain) && (gc != GroundCover::Bare || top.is_some());
+                // ground of another terrain only gets an edge along the surface, not a wall all
+                // the way down where two zones meet
+                let side = |t: Option<Terrain>| match t {
+                    Some(t) if t != terrain => top,
+                    t => t.is_none() && gc == GroundCover::FullyCovered,
+                };
+                let left = side(left);
+                let right = side(right);
                 let bottom = bottom != Some(terrain)
                     && (gc == GroundCover::FullyCovered || bottom.is_some());
 
-                let lmr = match (left, right) {
-                    (true, _) => LMR::L,
-                    (_, true) => LMR::R,
-                    (_, _) => LMR::M,
-                };
-                let tmb = match (top, bottom) {
-                    (true, _) => TMB::T,
-                    (_, true) => TMB::B,
-                    (_, _) => TMB::M,
+                let sides = Sides {
+                    left,
+                    right,
+                    top,
+                    bottom,
                 };
-                if lmr != LMR::M || tmb != TMB::M {
-                    let t = TilingTile::Exactly(Tile::Terrain(
-                        terrain,
-                        TerrainTile::BlockFace(lmr, tmb),
-                    ));
+                if let Some(tt) = rules.for_terrain(terrain).side_tile(sides) {
+                    let t = TilingTile::Exactly(Tile::Terrain(terrain, tt));
                     with_sides[[i + 1, j + 1]] = (t, t.info());
                 }
             }
@@ -1487,19 +1159,9 @@ pub fn compute_tiling(array: ndarray::Array2<TilingTile>) -> ndarray::Array2<(Ti
                     bottom.1.map(|ti| ti.tr).unwrap_or(TilingCorner::None),
                 );
 
-                let tt = if tl == TilingCorner::Slope {
-                    TerrainTile::SlopeInt(LR::L)
-                } else if tr == TilingCorner::Slope {
-                    TerrainTile::SlopeInt(LR::R)
-                } else {
-                    match (tl, tr, bl, br) {
-                        (TilingCorner::Inner, _, _, _) => TerrainTile::FaceInt(LR::L, TB::T),
-                        (_, TilingCorner::Inner, _, _) => TerrainTile::FaceInt(LR::R, TB::T),
-                        (_, _, TilingCorner::Inner, _) => TerrainTile::FaceInt(LR::L, TB::B),
-                        (_, _, _, TilingCorner::Inner) => TerrainTile::FaceInt(LR::R, TB::B),
-                        _ => TerrainTile::BlockFace(LMR::M, TMB::M),
-                    }
-                };
+                let tt = rules
+                    .for_terrain(terrain)
+                    .corner_tile(Corners { tl, tr, bl, br });
                 out[[i, j]].0 = Tile::Terrain(terrain, tt)
             }
         }
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, fmt, fmt::Write};
+
+use enum_iterator::all;
+use itertools::iproduct;
+use serde::Deserialize;
+
+use super::tile::{Index, Tile};
+
+const HEADER: &str = "\
+// Where each tile is on the sheet, in tiles from the top left, kept by hand. Running the game
+// with `--update-tileset <sheet.png>` sizes this to that sheet and adds any tiles that are new
+// since, commented out until they're given a cell. The cells below are kept as they are.
+// Tiles under `same_as` are drawn no differently from another tile, so they take its cell
+";
+
+#[derive(Deserialize)]
+struct Manifest {
+    columns: u16,
+    rows: u16,
+    tiles: HashMap<Tile, Index>,
+    #[serde(default)]
+    same_as: HashMap<Tile, Tile>,
+}
+
+// Where on the sheet each tile is drawn from, as read from `assets/numbering.tileset.ron`.
+// Coordinates are in tiles, from the top left
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "Manifest")]
+pub struct Tileset {
+    pub columns: u16,
+    pub rows: u16,
+    tiles: HashMap<Tile, Index>,
+    // tiles drawn no differently from another, which are drawn from its cell
+    same_as: HashMap<Tile, Tile>,
+    // the other way round, for going from a cell back to its tile
+    cells: HashMap<Index, Tile>,
+}
+
+impl From<Manifest> for Tileset {
+    fn from(m: Manifest) -> Self {
+        let mut cells = HashMap::new();
+        // where tiles share a cell the one `Tile` lists first has it
+        for t in all::<Tile>() {
+            if let Some(&at) = m.tiles.get(&t) {
+                cells.entry(at).or_insert(t);
+            }
+        }
+        Self {
+            columns: m.columns,
+            rows: m.rows,
+            tiles: m.tiles,
+            same_as: m.same_as,
+            cells,
+        }
+    }
+}
+
+impl Tileset {
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, ron::error::SpannedError> {
+        ron::de::from_bytes(bytes)
+    }
+
+    pub fn index(&self, t: Tile) -> Option<Index> {
+        let t = self.same_as.get(&t).unwrap_or(&t);
+        self.tiles.get(t).copied()
+    }
+
+    // looked up through `Tile::from_index`
+    pub(super) fn tile_at(&self, at: Index) -> Option<Tile> {
+        self.cells.get(&at).copied()
+    }
+
+    // the tile's place in the sheet's texture atlas, counting along the rows
+    pub fn texture_index(&self, t: Tile) -> Option<u32> {
+        self.index(t)
+            .map(|(x, y)| y as u32 * self.columns as u32 + x as u32)
+    }
+
+    // Every tile the manifest gets wrong: tiles it leaves out, tiles off the edge of the sheet and
+    // tiles that share a cell without being listed as the same
+    pub fn validate(&self) -> Vec<TilesetProblem> {
+        let mut problems = Vec::new();
+        let mut cells: HashMap<Index, Vec<Tile>> = HashMap::new();
+        for t in all::<Tile>() {
+            match self.index(t) {
+                None => problems.push(TilesetProblem::Missing(t)),
+                Some((x, y)) if x >= self.columns || y >= self.rows => {
+                    problems.push(TilesetProblem::OutOfBounds(t, (x, y)))
+                }
+                Some(at) if !self.same_as.contains_key(&t) => cells.entry(at).or_default().push(t),
+                Some(_) => (),
+            }
+        }
+        let mut shared: Vec<_> = cells.into_iter().filter(|(_, ts)| ts.len() > 1).collect();
+        shared.sort_by_key(|&((x, y), _)| (y, x));
+        problems.extend(
+            shared
+                .into_iter()
+                .map(|(at, ts)| TilesetProblem::Shared(at, ts)),
+        );
+        problems
+    }
+
+    // Tiles that don't come back as themselves, or as the tile they're the same as, when looked
+    // up by their own cell: those with no cell, and all but the first of those sharing one
+    pub fn round_trip_failures(&self) -> Vec<Tile> {
+        all::<Tile>()
+            .filter(|&t| {
+                let back = *self.same_as.get(&t).unwrap_or(&t);
+                self.index(t)
+                    .map_or(true, |at| Tile::from_index(self, at) != Some(back))
+            })
+            .collect()
+    }
+
+    // cells on the sheet no tile is drawn from, along the rows
+    pub fn unused_cells(&self) -> Vec<Index> {
+        iproduct!(0..self.rows, 0..self.columns)
+            .map(|(y, x)| (x, y))
+            .filter(|at| !self.cells.contains_key(at))
+            .collect()
+    }
+
+    // The manifest as it would be written out for a sheet `columns` by `rows` tiles in size,
+    // with every tile there is in the order `Tile` lists them. Tiles that don't have a cell yet
+    // are left in, commented out, to be filled in by hand
+    pub fn to_ron(&self, columns: u16, rows: u16) -> String {
+        let mut out = String::new();
+        out.push_str(HEADER);
+        writeln!(
+            out,
+            "(\n    columns: {},\n    rows: {},\n    tiles: {{",
+            columns, rows
+        )
+        .unwrap();
+        for t in all::<Tile>().filter(|t| !self.same_as.contains_key(t)) {
+            match self.index(t) {
+                Some((x, y)) => writeln!(out, "        {}: ({}, {}),", name(t), x, y),
+                None => writeln!(out, "        // {}: (_, _),", name(t)),
+            }
+            .unwrap();
+        }
+        out.push_str("    },\n    same_as: {\n");
+        for t in all::<Tile>() {
+            if let Some(&same) = self.same_as.get(&t) {
+                writeln!(out, "        {}: {},", name(t), name(same)).unwrap();
+            }
+        }
+        out.push_str("    },\n)\n");
+        out
+    }
+}
+
+// how a tile is spelled in the manifest
+fn name(t: Tile) -> String {
+    ron::to_string(&t)
+        .unwrap()
+        .replace(',', ", ")
+        .replace(':', ": ")
+}
+
+impl Default for Tileset {
+    fn default() -> Self {
+        Self::from_ron(include_bytes!("../../assets/numbering.tileset.ron"))
+            .expect("the bundled tileset should parse")
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TilesetProblem {
+    Missing(Tile),
+    OutOfBounds(Tile, Index),
+    Shared(Index, Vec<Tile>),
+}
+
+impl fmt::Display for TilesetProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TilesetProblem::Missing(t) => write!(f, "{:?} has no cell", t),
+            TilesetProblem::OutOfBounds(t, at) => {
+                write!(f, "{:?} is at {:?}, off the edge of the sheet", t, at)
+            }
+            TilesetProblem::Shared(at, ts) => write!(f, "{:?} all use {:?}", ts, at),
+        }
+    }
+}
+
+// how big a PNG is, in pixels, from its header
+pub fn png_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    if bytes.get(..8)? != b"\x89PNG\r\n\x1a\n" || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let word = |at: usize| Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+    Some((word(16)?, word(20)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::tile::{Color4, RockType, Terrain, TerrainTile, LMR, LR, TMB};
+
+    #[test]
+    fn bundled_tileset_is_valid() {
+        assert_eq!(Tileset::default().validate(), Vec::new());
+    }
+
+    #[test]
+    fn bundled_tiles_come_back_from_their_cells() {
+        assert_eq!(Tileset::default().round_trip_failures(), Vec::new());
+    }
+
+    #[test]
+    fn tiles_come_back_with_their_parameters() {
+        let tileset = Tileset::default();
+        let cells = [
+            (
+                (11, 1),
+                Tile::Terrain(Terrain::Cake, TerrainTile::Half(true, LMR::R)),
+            ),
+            (
+                (8, 1),
+                Tile::Terrain(Terrain::Cake, TerrainTile::SingleHalf(true)),
+            ),
+            (
+                (2, 27),
+                Tile::Terrain(Terrain::Grass, TerrainTile::BlockFace(LMR::R, TMB::B)),
+            ),
+            ((14, 5), Tile::Building(RockType::Slate, LMR::R, TMB::B)),
+            ((12, 11), Tile::Gummy(Color4::R)),
+            (
+                (12, 44),
+                Tile::Button {
+                    pressed: true,
+                    color: Color4::Y,
+                },
+            ),
+        ];
+        for (at, t) in cells {
+            assert_eq!(Tile::from_index(&tileset, at), Some(t));
+        }
+    }
+
+    // The table the manifest replaced put these on the cells of `GirderSmall` and
+    // `FlagWoodLongRedFrame2`, which the sheet has them beside
+    #[test]
+    fn girders_and_green_flags_have_their_own_cells() {
+        let tileset = Tileset::default();
+        for (bolts, x) in [(false, 0), (true, 1)] {
+            assert_eq!(tileset.index(Tile::Girder { bolts }), Some((24 + x, 3)));
+            assert_eq!(
+                tileset.index(Tile::GirderHoles { bolts }),
+                Some((26 + x, 3))
+            );
+        }
+        assert_eq!(
+            tileset.index(Tile::FlagWoodLongGreenFrame2(LR::L)),
+            Some((10, 52))
+        );
+        assert_eq!(
+            tileset.index(Tile::FlagWoodLongGreenFrame2(LR::R)),
+            Some((11, 53))
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use itertools::iproduct;
+use itertools::Itertools;
+use noise::NoiseFn;
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::helpers::*;
+
+use super::{brushes::Gen, feature::*, tile::*, Place};
+
+const ATTEMPTS: u32 = 3;
+// cells this far above the height line are left open, so the solver can't wall off the sky
+const HEADROOM: i32 = 4;
+
+// right, up, left, down
+const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+// a set of tiles a cell could still become, one bit per tile in `Rules::tiles`
+type Domain = u32;
+
+fn tiles_of(d: Domain) -> impl Iterator<Item = usize> {
+    (0..Domain::BITS as usize).filter(move |&t| d & (1 << t) != 0)
+}
+
+// Undecided cells, least certain first and then in scan order. A cell goes in again each time it
+// narrows, and the entries it leaves behind are skipped when they come up
+#[derive(Default)]
+struct Queue(BinaryHeap<Reverse<(u32, usize, usize)>>);
+
+impl Queue {
+    fn push(&mut self, cells: &ndarray::Array2<Domain>, (i, j): (usize, usize)) {
+        let n = cells[(i, j)].count_ones();
+        if n > 1 {
+            self.0.push(Reverse((n, i, j)));
+        }
+    }
+
+    fn pop(&mut self, cells: &ndarray::Array2<Domain>) -> Option<(usize, usize)> {
+        while let Some(Reverse((n, i, j))) = self.0.pop() {
+            if cells[(i, j)].count_ones() == n {
+                return Some((i, j));
+            }
+        }
+        None
+    }
+}
+
+struct Rules {
+    terrain: Terrain,
+    tiles: Vec<Tile>,
+    // for each direction and tile, the tiles that may sit next to it that way
+    fits: [Vec<Domain>; 4],
+}
+
+impl Rules {
+    fn new(terrain: Terrain) -> Self {
+        let mut tiles = vec![Tile::Air];
+        for (lmr, tmb) in iproduct!([LMR::L, LMR::M, LMR::R], [TMB::T, TMB::M, TMB::B]) {
+            tiles.push(Tile::Terrain(terrain, TerrainTile::BlockFace(lmr, tmb)));
+        }
+        for lr in [LR::L, LR::R] {
+            tiles.push(Tile::Terrain(terrain, TerrainTile::Slope(lr)));
+            tiles.push(Tile::Terrain(terrain, TerrainTile::SlopeInt(lr)));
+            for tb in [TB::T, TB::B] {
+                tiles.push(Tile::Terrain(terrain, TerrainTile::FaceInt(lr, tb)));
+            }
+        }
+        for tt in [
+            TerrainTile::Single,
+            TerrainTile::SingleBare,
+            TerrainTile::Jagged,
+        ] {
+            tiles.push(Tile::Terrain(terrain, tt));
+        }
+        assert!(tiles.len() <= Domain::BITS as usize);
+
+        // the edge rules let two open faces meet, which between solid tiles leaves a crack, so
+        // only slopes may do it, where they make a valley
+        let air = TilingTile::Exactly(Tile::Air);
+        let beside = |a: Tile, b: Tile| {
+            let (ta, tb) = (TilingTile::Exactly(a), TilingTile::Exactly(b));
+            ta.fits_left_of(tb)
+                && (a == Tile::Air
+                    || b == Tile::Air
+                    || !ta.fits_left_of(air)
+                    || matches!(
+                        (a, b),
+                        (
+                            Tile::Terrain(_, TerrainTile::Slope(LR::R)),
+                            Tile::Terrain(_, TerrainTile::Slope(LR::L))
+                        )
+                    ))
+        };
+        let over = |a: Tile, b: Tile| {
+            let (ta, tb) = (TilingTile::Exactly(a), TilingTile::Exactly(b));
+            ta.fits_above(tb) && (a == Tile::Air || b == Tile::Air || !ta.fits_above(air))
+        };
+
+        let fits_with = |f: &dyn Fn(Tile, Tile) -> bool| {
+            tiles
+                .iter()
+                .map(|&a| {
+                    tiles
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, &b)| f(a, b))
+                        .fold(0, |d, (t, _)| d | 1 << t)
+                })
+                .collect()
+        };
+        let fits = [
+            fits_with(&beside),
+            fits_with(&|a, b| over(b, a)),
+            fits_with(&|a, b| beside(b, a)),
+            fits_with(&over),
+        ];
+
+        Self {
+            terrain,
+            tiles,
+            fits,
+        }
+    }
+
+    fn domain(&self, f: impl Fn(Tile) -> bool) -> Domain {
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|&(_, &t)| f(t))
+            .fold(0, |d, (t, _)| d | 1 << t)
+    }
+
+    fn weight(&self, t: usize, y: i32, height: i32) -> f64 {
+        let base = match self.tiles[t] {
+            Tile::Air => 1.0,
+            Tile::Terrain(_, TerrainTile::BlockFace(LMR::M, TMB::T | TMB::M)) => 3.0,
+            Tile::Terrain(_, TerrainTile::BlockFace(_, TMB::T | TMB::M)) => 1.0,
+            Tile::Terrain(_, TerrainTile::BlockFace(_, TMB::B)) => 0.3,
+            Tile::Terrain(_, TerrainTile::Slope(_) | TerrainTile::SlopeInt(_)) => 1.0,
+            Tile::Terrain(_, TerrainTile::FaceInt(_, _)) => 0.5,
+            _ => 0.2,
+        };
+        // lean towards the height map, so the ground still follows the level's terrain noise, and
+        // keep faces out of the inside of the ground
+        let solid = self.tiles[t] != Tile::Air;
+        let buried = y < height - 1
+            && self.tiles[t] != Tile::Terrain(self.terrain, TerrainTile::BlockFace(LMR::M, TMB::M));
+        base * if solid == (y < height) { 4.0 } else { 0.25 } * if buried { 0.1 } else { 1.0 }
+    }
+
+    // AC-3 style: narrow the neighbours of every changed cell until nothing changes
+    fn propagate(
+        &self,
+        cells: &mut ndarray::Array2<Domain>,
+        queue: &mut Queue,
+        mut dirty: Vec<(usize, usize)>,
+    ) -> Option<()> {
+        let (w, h) = cells.dim();
+        while let Some((i, j)) = dirty.pop() {
+            for (dir, (di, dj)) in DIRECTIONS.into_iter().enumerate() {
+                let (ni, nj) = (i as i32 + di, j as i32 + dj);
+                if ni < 0 || nj < 0 || ni >= w as i32 || nj >= h as i32 {
+                    continue;
+                }
+                let n = (ni as usize, nj as usize);
+                let allowed = tiles_of(cells[(i, j)]).fold(0, |d, t| d | self.fits[dir][t]);
+                let narrowed = cells[n] & allowed;
+                if narrowed == 0 {
+                    return None;
+                }
+                if narrowed != cells[n] {
+                    cells[n] = narrowed;
+                    queue.push(cells, n);
+                    dirty.push(n);
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn solve(
+        &self,
+        gen: &Gen,
+        box2: Box2<i32>,
+        heights: &[i32],
+        attempt: u32,
+    ) -> Option<ndarray::Array2<Domain>> {
+        let (w, h) = (box2.x.size() as usize, box2.y.size() as usize);
+        let air = self.domain(|t| t == Tile::Air);
+        let open_top = self.domain(|t| t == Tile::Air || TilingTile::Exactly(t).is_top());
+        // ground carries on through the bottom of the band, same as the height map's
+        let floor = self.domain(|t| {
+            t == Tile::Air || !TilingTile::Exactly(t).fits_above(TilingTile::Exactly(Tile::Air))
+        });
+        let column = |y: i32, height: i32| {
+            self.domain(|t| {
+                t == if y < height - 1 {
+                    Tile::Terrain(self.terrain, TerrainTile::BlockFace(LMR::M, TMB::M))
+                } else if y == height - 1 {
+                    Tile::Terrain(self.terrain, TerrainTile::BlockFace(LMR::M, TMB::T))
+                } else {
+                    Tile::Air
+                }
+            })
+        };
+
+        let mut cells = ndarray::Array2::from_elem((w, h), self.domain(|_| true));
+        for (i, j) in iproduct!(0..w, 0..h) {
+            let y = box2.y.lo_incl + j as i32;
+            let d = &mut cells[(i, j)];
+            if j == h - 1 {
+                *d &= open_top;
+            }
+            if j == 0 {
+                *d &= floor;
+            }
+            if y >= heights[i] + HEADROOM {
+                *d &= air;
+            }
+            // the edge columns are fixed to plain ground so the neighbouring zones line up
+            if i == 0 || i == w - 1 {
+                *d &= column(y, heights[i]);
+            }
+        }
+        let mut queue = Queue::default();
+        for c in iproduct!(0..w, 0..h) {
+            queue.push(&cells, c);
+        }
+        self.propagate(&mut cells, &mut queue, iproduct!(0..w, 0..h).collect())?;
+
+        loop {
+            let Some((i, j)) = queue.pop(&cells) else {
+                return Some(cells);
+            };
+            let (x, y) = (box2.x.lo_incl + i as i32, box2.y.lo_incl + j as i32);
+
+            let options: Vec<_> = tiles_of(cells[(i, j)])
+                .map(|t| (t, self.weight(t, y, heights[i])))
+                .collect();
+            let total: f64 = options.iter().map(|(_, w)| w).sum();
+            // each attempt gets its own stream, a thousand rows apart
+            let mut r = gen
+                .theme
+                .get([x as f64, ((67 + attempt) * 1000) as f64 + y as f64])
+                * total;
+            let t = options
+                .iter()
+                .find(|&&(_, w)| {
+                    r -= w;
+                    r < 0.0
+                })
+                .unwrap_or(options.last().unwrap())
+                .0;
+
+            cells[(i, j)] = 1 << t;
+            self.propagate(&mut cells, &mut queue, vec![(i, j)])?;
+        }
+    }
+}
+
+// Lays a zone's ground out by wave function collapse over the terrain tiles, using the same edge
+// rules as `compute_tiling`. Only the shape of the ground is kept: where it's solid and where it
+// slopes. It goes in as blocks that `compute_tiling` draws afresh, all in the zone's main terrain,
+// so the alternate terrain is left to the height map. Returns false if every attempt ran into a
+// contradiction, leaving the schema untouched so the caller can fall back to the brushes
+pub fn wfc_brush(schema: &mut Schema, gen: &Gen, zone: Zone, box2: Box2<i32>) -> bool {
+    let rules = Rules::new(zone.info().terrain);
+    let height_at = |i: i32| n_to_box1(gen.terrain.get([i as f64, 0.0]), box2.y);
+    // the zone on the left ends at its own height, the one on the right starts from ours
+    let heights: Vec<_> = box2
+        .x
+        .iter()
+        .map(|i| height_at(if i == box2.x.lo_incl { i - 1 } else { i }))
+        .collect();
+
+    let Some(cells) = (0..ATTEMPTS).find_map(|attempt| rules.solve(gen, box2, &heights, attempt))
+    else {
+        return false;
+    };
+
+    let tile_at = |i: usize, j: usize| rules.tiles[cells[(i, j)].trailing_zeros() as usize];
+    let (w, h) = cells.dim();
+    for (i, j) in iproduct!(0..w, 0..h) {
+        if let t @ Tile::Terrain(_, TerrainTile::Slope(lr)) = tile_at(i, j) {
+            let p = Place::new(box2.x.lo_incl + i as i32, box2.y.lo_incl + j as i32);
+            schema.add(Feature::Tile(p, t));
+            schema.add(Feature::SlopedGround {
+                start: p,
+                height: -i32::from(lr),
+            });
+        }
+    }
+
+    // The rest of the ground goes in as blocks, one per run of solid cells in a column, with
+    // neighbouring columns merged where their runs match, so that it tiles, collides and gets
+    // walked on like the height map's. Runs on the floor carry on into the band below
+    let solid = |i: usize, j: usize| {
+        !matches!(
+            tile_at(i, j),
+            Tile::Air | Tile::Terrain(_, TerrainTile::Slope(_))
+        )
+    };
+    let runs = iproduct!(0..w, 0..h)
+        .filter(|&(i, j)| solid(i, j))
+        .map(|(i, j)| {
+            let p = Place::new(box2.x.lo_incl + i as i32, box2.y.lo_incl + j as i32);
+            (Box1::from_point(p.x), Box1::from_point(p.y))
+        })
+        .coalesce(|(x1, y1), (x2, y2)| {
+            if x1 == x2 && y1.hi_excl == y2.lo_incl {
+                Ok((x1, y1.union_cover(y2)))
+            } else {
+                Err(((x1, y1), (x2, y2)))
+            }
+        });
+    let blocks = runs
+        .sorted_by_key(|&(x, y)| (y.lo_incl, y.hi_excl, x.lo_incl))
+        .coalesce(|(x1, y1), (x2, y2)| {
+            if y1 == y2 && x1.hi_excl == x2.lo_incl {
+                Ok((x1.union_cover(x2), y1))
+            } else {
+                Err(((x1, y1), (x2, y2)))
+            }
+        });
+    for (x, y) in blocks {
+        let gc = if y.lo_incl == box2.y.lo_incl {
+            GroundCover::TopCovered
+        } else {
+            GroundCover::FullyCovered
+        };
+        schema.add(Feature::GroundBlock(
+            gc,
+            rules.terrain,
+            Box2::from_box1s(x, y),
+        ));
+    }
+
+    true
+}